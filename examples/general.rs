@@ -4,7 +4,7 @@ use tokio::time::{sleep, Duration};
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Find by specifying device name
-    let mut d = motu_avb_api::from_name("624", None).await?;
+    let d = motu_avb_api::from_name("624", None).await?;
     d.connect().await?;
 
     // Or discover avaliable devices on the network
@@ -0,0 +1,31 @@
+#![no_main]
+
+use dashmap::DashMap;
+use libfuzzer_sys::fuzz_target;
+use motu_avb_api::{extchannel, KeyPath, Value};
+use serde_json::Value as SerdeValue;
+use std::sync::Arc;
+
+fuzz_target!(|entries: Vec<(bool, &str, &str)>| {
+    let cache = Arc::new(DashMap::new());
+
+    for (input, rest, raw) in entries {
+        // Random keys almost never look like bank keys, give the fuzzer a head start
+        let key = match input {
+            true => format!("ext/ibank/{}", rest),
+            false => format!("ext/obank/{}", rest),
+        };
+
+        let raw = serde_json::from_str(raw).unwrap_or_else(|_| SerdeValue::String(raw.to_string()));
+        let path = match key.parse::<KeyPath>() {
+            Ok(k) => k,
+            Err(_) => continue,
+        };
+        if let Ok(v) = Value::try_from(raw).and_then(|v| v.decode(&key)) {
+            cache.insert(path, v);
+        }
+    }
+
+    let _ = extchannel::build("ibank", cache.clone());
+    let _ = extchannel::build("obank", cache);
+});
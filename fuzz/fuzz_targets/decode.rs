@@ -0,0 +1,31 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use motu_avb_api::Value;
+use serde_json::Value as SerdeValue;
+
+fuzz_target!(|input: (&str, &str)| {
+    let (key, raw) = input;
+
+    // Most datastore values are strings, try the input both ways
+    if let Ok(raw) = serde_json::from_str::<SerdeValue>(raw) {
+        check(key, raw);
+    }
+    check(key, SerdeValue::String(raw.to_string()));
+});
+
+fn check(key: &str, raw: SerdeValue) {
+    let v = match Value::try_from(raw.clone()).and_then(|v| v.decode(key)) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    // Compare the text, serde_json keeps `-0` and `0` apart but prints them the same
+    assert_eq!(v.encode().to_string(), raw.to_string());
+
+    let _ = u32::try_from(&v);
+    let _ = i32::try_from(&v);
+    let _ = <(i32, i32)>::try_from(&v);
+    let _ = bool::try_from(&v);
+    let _ = v.to_string();
+}
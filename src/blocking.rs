@@ -0,0 +1,144 @@
+//! A synchronous `Device` for scripts and tools that don't run an async runtime.
+//!
+//! Each [`Device`] starts a tokio runtime with a single worker thread, which runs the
+//! polling and mapping tasks in the background. Methods block the calling thread until the
+//! async counterpart finishes, so they must not be called from within an async runtime.
+//!
+//! ```no_run
+//! use motu_avb_api::blocking::Device;
+//! use motu_avb_api::device::DeviceType;
+//!
+//! # fn main() -> Result<(), motu_avb_api::device::DeviceError> {
+//! let d = Device::new("624", "624.local", 80, "0001f2fffe00624a", DeviceType::Device)?;
+//! d.connect()?;
+//!
+//! for update in d.updates()? {
+//!     println!("{:?}", update);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::device::{ConnectionState, DeviceError, DeviceType};
+use crate::extchannel::ChannelBank;
+use crate::{KeyPath, Request, Update, Value};
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+/// Blocking handle to a MOTU device, cheap to clone like the async [`crate::Device`]
+#[derive(Debug, Clone)]
+pub struct Device {
+    device: crate::Device,
+    rt: Arc<Runtime>,
+}
+
+impl Device {
+    pub fn new(
+        name: &str,
+        hostname: &str,
+        port: u16,
+        uid: &str,
+        device_type: DeviceType,
+    ) -> Result<Device, DeviceError> {
+        Self::from_async(crate::Device::new(name, hostname, port, uid, device_type))
+    }
+
+    /// Wraps a device built with [`crate::DeviceBuilder`] or `Device::with_transport`
+    pub fn from_async(device: crate::Device) -> Result<Device, DeviceError> {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("motu-avb-api")
+            .enable_all()
+            .build()?;
+
+        Ok(Device {
+            device,
+            rt: Arc::new(rt),
+        })
+    }
+
+    /// The async handle, for anything this facade doesn't cover
+    pub fn as_async(&self) -> &crate::Device {
+        &self.device
+    }
+
+    pub fn connect(&self) -> Result<(), DeviceError> {
+        self.rt.block_on(self.device.connect())
+    }
+
+    pub fn disconnect(&self) -> Result<(), DeviceError> {
+        self.rt.block_on(self.device.disconnect())
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        self.device.connection_state()
+    }
+
+    pub fn get_value(&self, key: &str) -> Option<Value> {
+        self.device.get_value(key)
+    }
+
+    pub fn get(&self) -> Arc<DashMap<KeyPath, Value>> {
+        self.device.get()
+    }
+
+    pub fn set(&self, r: Request) -> Result<(), DeviceError> {
+        self.rt.block_on(self.device.set(r))
+    }
+
+    pub fn set_keys<K: AsRef<str>>(&self, data: &[(K, Value)]) -> Result<(), DeviceError> {
+        self.rt.block_on(self.device.set_keys(data))
+    }
+
+    pub fn set_confirmed(&self, r: Request, timeout: Duration) -> Result<Value, DeviceError> {
+        self.rt.block_on(self.device.set_confirmed(r, timeout))
+    }
+
+    pub fn input_banks(&self) -> Result<Arc<DashMap<u32, ChannelBank>>, DeviceError> {
+        self.device.input_banks()
+    }
+
+    pub fn output_banks(&self) -> Result<Arc<DashMap<u32, ChannelBank>>, DeviceError> {
+        self.device.output_banks()
+    }
+
+    /// Iterator over the updates from now on, ends once the device disconnects
+    pub fn updates(&self) -> Result<Updates, DeviceError> {
+        Ok(Updates {
+            rx: self.device.updates()?,
+        })
+    }
+}
+
+/// Blocking iterator over device updates.
+/// Missed updates show up as a single `Update::Resync`, like with `Device::watch`.
+#[derive(Debug)]
+pub struct Updates {
+    rx: tokio::sync::broadcast::Receiver<Update>,
+}
+
+impl Updates {
+    /// Returns the next update if there is one, without blocking
+    pub fn try_next(&mut self) -> Option<Update> {
+        match self.rx.try_recv() {
+            Ok(v) => Some(v),
+            Err(TryRecvError::Lagged(_)) => Some(Update::Resync),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => None,
+        }
+    }
+}
+
+impl Iterator for Updates {
+    type Item = Update;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.rx.blocking_recv() {
+            Ok(v) => Some(v),
+            Err(RecvError::Lagged(_)) => Some(Update::Resync),
+            Err(RecvError::Closed) => None,
+        }
+    }
+}
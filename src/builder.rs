@@ -0,0 +1,179 @@
+use crate::device::{Backoff, Device, DeviceError, DeviceType, PollingMode};
+use crate::transport::{
+    HttpTransport, Transport, DEFAULT_LONG_POLL_TIMEOUT, DEFAULT_REQUEST_TIMEOUT,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Configures a [`Device`] beyond what `Device::new` offers.
+///
+/// ```no_run
+/// # fn build() -> Result<motu_avb_api::Device, motu_avb_api::device::DeviceError> {
+/// use motu_avb_api::{Device, PollingMode};
+/// use std::time::Duration;
+///
+/// let d = Device::builder("624.local", 80)
+///     .name("Studio")
+///     .client_id(0x5eed)
+///     .user_agent("my-mixer/1.0")
+///     .request_timeout(Duration::from_secs(2))
+///     .polling(PollingMode::Interval(Duration::from_millis(500)))
+///     .build()?;
+/// # Ok(d)
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct DeviceBuilder {
+    pub(crate) name: String,
+    pub(crate) hostname: String,
+    pub(crate) port: u16,
+    pub(crate) uid: String,
+    pub(crate) device_type: DeviceType,
+
+    pub(crate) client_id: u32,
+    pub(crate) polling: PollingMode,
+    pub(crate) backoff: Backoff,
+
+    client: Option<reqwest::Client>,
+    connect_timeout: Option<Duration>,
+    request_timeout: Duration,
+    long_poll_timeout: Duration,
+    user_agent: Option<String>,
+    base_path: Option<String>,
+    transport: Option<Arc<dyn Transport>>,
+}
+
+impl DeviceBuilder {
+    pub fn new(hostname: &str, port: u16) -> Self {
+        DeviceBuilder {
+            name: hostname.to_string(),
+            hostname: hostname.to_string(),
+            port,
+            uid: String::new(),
+            device_type: DeviceType::Unknown,
+
+            client_id: rand::random(),
+            polling: PollingMode::default(),
+            backoff: Backoff::default(),
+
+            client: None,
+            connect_timeout: None,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            long_poll_timeout: DEFAULT_LONG_POLL_TIMEOUT,
+            user_agent: None,
+            base_path: None,
+            transport: None,
+        }
+    }
+
+    /// Defaults to the hostname
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn uid(mut self, uid: &str) -> Self {
+        self.uid = uid.to_string();
+        self
+    }
+
+    pub fn device_type(mut self, device_type: DeviceType) -> Self {
+        self.device_type = device_type;
+        self
+    }
+
+    /// The id the device knows us by, random unless set. Reusing the id of an earlier
+    /// session (see `Device::client_id`) picks up where its long poll left off.
+    pub fn client_id(mut self, client_id: u32) -> Self {
+        self.client_id = client_id;
+        self
+    }
+
+    pub fn polling(mut self, polling: PollingMode) -> Self {
+        self.polling = polling;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Uses `client` for every request instead of a new one.
+    /// `connect_timeout` can't be applied to a client that is already built and is ignored.
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// How long to wait for a TCP connection. Unset by default, the request timeout still
+    /// applies.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Applies to everything but long polls, 5 seconds unless set
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// How long to wait on a long poll before checking the device is still there,
+    /// 30 seconds unless set. The device answers long polls after about 15 seconds.
+    pub fn long_poll_timeout(mut self, timeout: Duration) -> Self {
+        self.long_poll_timeout = timeout;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = Some(user_agent.to_string());
+        self
+    }
+
+    /// For devices behind a proxy that serves the API below a path, e.g. `/motu/datastore`
+    pub fn base_path(mut self, path: &str) -> Self {
+        self.base_path = Some(path.to_string());
+        self
+    }
+
+    /// Talks to the datastore through `transport`, none of the HTTP settings apply then
+    pub fn transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    pub fn build(self) -> Result<Device, DeviceError> {
+        let transport = match &self.transport {
+            Some(t) => t.clone(),
+            None => Arc::new(self.http_transport()?),
+        };
+
+        Ok(Device::from_builder(&self, transport))
+    }
+
+    fn http_transport(&self) -> Result<HttpTransport, DeviceError> {
+        let client = match &self.client {
+            Some(c) => c.clone(),
+            None => {
+                let mut c = reqwest::Client::builder();
+                if let Some(v) = self.connect_timeout {
+                    c = c.connect_timeout(v);
+                }
+                c.build()?
+            }
+        };
+
+        let mut t = HttpTransport::with_client(client, &self.hostname, self.port)
+            .request_timeout(self.request_timeout)
+            .long_poll_timeout(self.long_poll_timeout);
+        if let Some(v) = &self.base_path {
+            t = t.base_path(v);
+        }
+        if let Some(v) = &self.user_agent {
+            t = t.user_agent(v);
+        }
+
+        Ok(t)
+    }
+}
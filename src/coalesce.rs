@@ -0,0 +1,180 @@
+use crate::device::{Device, DeviceError};
+use crate::schema::Schema;
+use crate::{KeyPath, Request, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+
+/// Rate limited writes for continuous controls like faders and trim knobs.
+///
+/// `set` puts the value into the cache and emits an `Update::Internal` right away, the
+/// `PATCH` is sent by a background writer. Values written while a `PATCH` is in flight are
+/// collapsed, only the last one per key is sent, together with every other pending key, in
+/// the next `PATCH`. At most one `PATCH` is in flight and they are at least `min_interval`
+/// apart.
+///
+/// Keys of a `PATCH` that fails go back to what the device held before and an
+/// `Update::Rollback` is emitted for each, unless they were written again in the meantime.
+///
+/// The writer stops once the last handle is dropped, after sending whatever is pending.
+#[derive(Debug, Clone)]
+pub struct Coalescer {
+    device: Device,
+    shared: Arc<Shared>,
+    wake: Arc<watch::Sender<()>>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    /// Bumped every time a `PATCH` finishes
+    written: watch::Sender<u64>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    pending: BTreeMap<KeyPath, Value>,
+    /// What the device held before the first pending or in flight write of each key
+    prior: BTreeMap<KeyPath, Option<Value>>,
+    in_flight: bool,
+    /// First failed write since the last `flush`
+    error: Option<Arc<DeviceError>>,
+}
+
+impl Coalescer {
+    /// Starts the background writer, has to be called from within a tokio runtime
+    pub(crate) fn new(device: Device, min_interval: Duration) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            written: watch::channel(0).0,
+        });
+        let (wake, rx) = watch::channel(());
+
+        tokio::spawn(Self::write(
+            device.clone(),
+            shared.clone(),
+            rx,
+            min_interval,
+        ));
+
+        Coalescer {
+            device,
+            shared,
+            wake: Arc::new(wake),
+        }
+    }
+
+    /// Applies a write locally and queues it. Fails right away if the write doesn't fit the
+    /// schema, failures to send it are returned by `flush`.
+    pub fn set(&self, r: Request) -> Result<(), DeviceError> {
+        Schema::motu().validate(r.key.as_str(), &r.val)?;
+
+        let mut state = self.shared.state.lock().unwrap();
+
+        let prior = self.device.get_value(r.key.as_str());
+        self.device.apply_local(r.key.clone(), r.val.clone());
+
+        state.prior.entry(r.key.clone()).or_insert(prior);
+        state.pending.insert(r.key, r.val);
+        drop(state);
+
+        self.wake.send_replace(());
+        Ok(())
+    }
+
+    /// Number of keys waiting for the next `PATCH`
+    pub fn pending(&self) -> usize {
+        self.shared.state.lock().unwrap().pending.len()
+    }
+
+    /// Waits until everything queued so far has been sent.
+    /// Returns the first failed write since the last call as `DeviceError::RolledBack`, if any.
+    pub async fn flush(&self) -> Result<(), DeviceError> {
+        let mut written = self.shared.written.subscribe();
+
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.pending.is_empty() && !state.in_flight {
+                    return match state.error.take() {
+                        Some(e) => Err(DeviceError::RolledBack(e)),
+                        None => Ok(()),
+                    };
+                }
+            }
+
+            // The sender lives in `shared`, this can't fail while we hold it
+            let _ = written.changed().await;
+        }
+    }
+
+    async fn write(
+        device: Device,
+        shared: Arc<Shared>,
+        mut wake: watch::Receiver<()>,
+        min_interval: Duration,
+    ) {
+        let mut interval = tokio::time::interval(min_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            let closed = wake.changed().await.is_err();
+
+            // Keep writing until a tick finds nothing new
+            loop {
+                interval.tick().await;
+
+                let batch: Vec<(KeyPath, Value)> = {
+                    let mut state = shared.state.lock().unwrap();
+                    let batch = std::mem::take(&mut state.pending);
+                    state.in_flight = !batch.is_empty();
+                    batch.into_iter().collect()
+                };
+
+                if batch.is_empty() {
+                    break;
+                }
+
+                let res = device.send(&batch).await;
+
+                {
+                    let mut state = shared.state.lock().unwrap();
+                    state.in_flight = false;
+
+                    let failed = res.err().map(Arc::new);
+                    for (key, val) in batch {
+                        let queued = state.pending.contains_key(&key);
+                        match (&failed, queued) {
+                            // The next write of the key starts from what the device holds now
+                            (None, true) => {
+                                state.prior.insert(key, Some(val));
+                            }
+                            (None, false) => {
+                                state.prior.remove(&key);
+                            }
+                            // The next write is sent anyway and restores the same prior if it
+                            // fails too
+                            (Some(_), true) => {}
+                            (Some(e), false) => {
+                                if let Some(prior) = state.prior.remove(&key) {
+                                    device.undo_local(key, val, prior, e.clone());
+                                }
+                            }
+                        }
+                    }
+
+                    if let Some(e) = failed {
+                        state.error.get_or_insert(e);
+                    }
+                }
+                shared.written.send_modify(|v| *v += 1);
+            }
+
+            if closed {
+                return;
+            }
+        }
+    }
+}
@@ -1,16 +1,138 @@
+use crate::builder::DeviceBuilder;
+use crate::coalesce::Coalescer;
 use crate::extchannel::{self, ChannelBank, ChannelBankType, ParseError};
+use crate::key::{KeyPath, KeyPathError};
+use crate::schema::{Schema, SchemaError};
+use crate::session::{Recorder, ReplayPace, Session, SessionError, SessionEventKind};
+use crate::snapshot::{RestoreReport, Snapshot};
+use crate::transaction::Transaction;
+use crate::transport::{HttpTransport, PollResponse, Transport};
 use crate::value::{Value, ValueError};
+use crate::watch::{Captures, Pattern, PatternError, WatchUpdate};
 use dashmap::DashMap;
-use rand::Rng;
-use reqwest::{header::HeaderValue, StatusCode};
+use futures::future::BoxFuture;
+use reqwest::StatusCode;
 use serde::ser::{Serialize as SerializeImpl, SerializeStruct};
 use serde::{Deserialize, Serialize};
 use serde_json::Value as SerdeValue;
 use std::hash::{Hash, Hasher};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::Duration;
 use std::{collections::HashMap, fmt::Display};
 use thiserror::Error;
-use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_stream::wrappers::{BroadcastStream, WatchStream};
+use tokio_stream::{Stream, StreamExt};
+use tracing::Instrument;
+
+type Banks = DashMap<u32, ChannelBank>;
+type SharedRecorder = Arc<RwLock<Option<Arc<Recorder>>>>;
+type Diagnostics = tokio::sync::broadcast::Sender<Diagnostic>;
+
+/// How often `set_confirmed` reads a key back while waiting for the device to settle
+const CONFIRM_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Owns the background tasks of a connection, stops them when the last handle is dropped
+#[derive(Debug)]
+struct ConnectionHandle {
+    cancel: watch::Sender<bool>,
+    tasks: Mutex<Vec<JoinHandle<Result<(), DeviceError>>>>,
+}
+
+impl ConnectionHandle {
+    fn new() -> Self {
+        ConnectionHandle {
+            cancel: watch::channel(false).0,
+            tasks: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn push(&self, task: JoinHandle<Result<(), DeviceError>>) {
+        self.tasks.lock().unwrap().push(task);
+    }
+
+    fn cancel(&self) {
+        self.cancel.send_replace(true);
+    }
+
+    /// Signals the tasks to stop and waits for them to exit, returns the first error
+    /// one of them ended with
+    async fn shutdown(&self) -> Result<(), DeviceError> {
+        self.cancel();
+
+        let tasks: Vec<_> = self.tasks.lock().unwrap().drain(..).collect();
+        let mut res = Ok(());
+        for t in tasks {
+            let r = t.await?;
+            if res.is_ok() {
+                res = r;
+            }
+        }
+
+        res
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// The polling and mapping loops of a connection made with `Device::connect_driven`.
+///
+/// Nothing happens until `run` is awaited, on whichever task or executor the caller
+/// chooses. Panics in either loop surface there too. Dropping the driver, run or not,
+/// leaves the device `Disconnected`.
+#[must_use = "the device receives no updates until the driver runs"]
+pub struct ConnectionDriver {
+    poll: BoxFuture<'static, Result<(), DeviceError>>,
+    map: BoxFuture<'static, ()>,
+    guard: DriverGuard,
+}
+
+/// Drops the connection of a driver that is gone, unless `disconnect` got to it first
+struct DriverGuard {
+    device: Weak<DeviceInner>,
+    id: u64,
+}
+
+impl Drop for DriverGuard {
+    fn drop(&mut self) {
+        if let Some(inner) = self.device.upgrade() {
+            Device { inner }.connection_lost(self.id);
+        }
+    }
+}
+
+impl ConnectionDriver {
+    /// Drives the connection until `disconnect` is called, which returns `Ok(())`.
+    /// Fails with the last reconnect error once the device stays unreachable for longer
+    /// than the backoff allows. By then the device is `Disconnected` and can connect again.
+    pub async fn run(self) -> Result<(), DeviceError> {
+        let ConnectionDriver { poll, map, guard } = self;
+
+        // The mapping loop only ends after the polling loop, whose result is the one to keep
+        let res = tokio::select! {
+            biased;
+            res = poll => res,
+            _ = map => Ok(()),
+        };
+
+        // Gone before the caller sees an error, so it can connect again right away
+        drop(guard);
+        res
+    }
+}
+
+impl std::fmt::Debug for ConnectionDriver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionDriver").finish_non_exhaustive()
+    }
+}
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize, Eq, PartialEq, Hash)]
@@ -22,30 +144,211 @@ struct ShadowDevice {
     device_type: DeviceType,
 }
 
-#[allow(dead_code)]
+/// Handle to a MOTU device.
+///
+/// Cloning is cheap and every clone refers to the same connection, cache and channel banks,
+/// so a device can be handed to as many tasks as needed. The background tasks are stopped
+/// once the last handle is dropped.
 #[derive(Debug, Clone)]
 pub struct Device {
+    inner: Arc<DeviceInner>,
+}
+
+#[derive(Debug)]
+struct DeviceInner {
     name: String,
     hostname: String,
     port: u16,
     uid: String,
     device_type: DeviceType,
 
-    connected: bool,
+    state: Arc<watch::Sender<ConnectionState>>,
+
+    transport: Arc<dyn Transport>,
+    polling: PollingMode,
+
+    backoff: Mutex<Backoff>,
+    write_mode: Mutex<WriteMode>,
 
-    url: String,
-    health: String,
-    client: reqwest::Client,
+    cache: Arc<DashMap<KeyPath, Value>>,
+    recorder: SharedRecorder,
+    diagnostics: Diagnostics,
 
-    conn_cancel: Option<Sender<()>>,
+    // Optimistic writes that haven't been confirmed yet
+    pending: DashMap<KeyPath, PendingWrite>,
+    write_generation: AtomicU64,
+
+    // Serializes connect and disconnect between handles
+    lifecycle: tokio::sync::Mutex<()>,
+    conn: RwLock<Option<Connection>>,
+    connection_id: AtomicU64,
+
+    client_id: u32,
 
-    cache: Arc<DashMap<String, Value>>,
-    updates: Option<tokio::sync::broadcast::Sender<Update>>,
+    // Parent of everything this device logs, carries uid and hostname
+    span: tracing::Span,
+}
 
-    input_banks: Option<Arc<DashMap<u32, ChannelBank>>>,
-    output_banks: Option<Arc<DashMap<u32, ChannelBank>>>,
+/// Everything that only exists while the device is connected
+#[derive(Debug)]
+struct Connection {
+    // Tells a connection apart from the ones made after it
+    id: u64,
+    handle: ConnectionHandle,
+    updates: tokio::sync::broadcast::Sender<Update>,
+    input_banks: Arc<Banks>,
+    output_banks: Arc<Banks>,
+}
 
+/// What the polling task needs from the device
+struct Poller {
+    transport: Arc<dyn Transport>,
     client_id: u32,
+    mode: PollingMode,
+    etag: Option<String>,
+    cache: Arc<DashMap<KeyPath, Value>>,
+    updates: tokio::sync::broadcast::Sender<Update>,
+    recorder: SharedRecorder,
+    diagnostics: Diagnostics,
+    /// Bumped after every response that changed the cache
+    generation: Arc<AtomicU64>,
+}
+
+impl Poller {
+    async fn poll(&mut self) -> Result<(), DeviceError> {
+        // Without an etag the device answers right away, which is what interval polling wants
+        let etag = match self.mode {
+            PollingMode::LongPoll => self.etag.as_deref(),
+            PollingMode::Interval(_) => None,
+        };
+
+        let start = Instant::now();
+        let (new_etag, mut m) = match self.transport.poll(self.client_id, etag).await? {
+            PollResponse::NotModified => {
+                tracing::trace!(status = "not_modified", etag, elapsed = ?start.elapsed(), "poll");
+                return Ok(());
+            }
+            PollResponse::Changed { etag, body } => (etag, body),
+        };
+
+        tracing::debug!(
+            status = "changed",
+            etag = new_etag.as_deref(),
+            keys = m.len(),
+            elapsed = ?start.elapsed(),
+            "poll"
+        );
+
+        // Store the etag which we use to only get updates
+        self.etag = new_etag;
+
+        // Held until the cache is updated, so `start_recording` can't miss this response
+        let recorder = self.recorder.read().unwrap();
+        if let Some(r) = recorder.as_ref() {
+            r.record(SessionEventKind::Poll {
+                etag: self.etag.clone(),
+                body: m.clone(),
+            });
+        }
+
+        // Interval polls return the whole datastore, only announce what changed
+        if let PollingMode::Interval(_) = self.mode {
+            let cache = &self.cache;
+            m.retain(|k, raw| match cache.get(k.as_str()) {
+                Some(v) => v.encode() != *raw,
+                None => true,
+            });
+        }
+
+        Device::ingest(m, &self.cache, &self.updates, &self.diagnostics);
+        drop(recorder);
+        self.generation.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+}
+
+/// What the mapping task needs from the device
+struct Mapper {
+    updates: tokio::sync::broadcast::Receiver<Update>,
+    resync_tx: tokio::sync::broadcast::Sender<Update>,
+    cache: Arc<DashMap<KeyPath, Value>>,
+    input_banks: Arc<Banks>,
+    output_banks: Arc<Banks>,
+    diagnostics: Diagnostics,
+    /// Shared with the `Poller`, tells whether the cache changed since the last rebuild
+    generation: Arc<AtomicU64>,
+    rebuilt_at: u64,
+}
+
+impl Mapper {
+    /// Listens to updates and maps them onto the channel banks until cancelled
+    async fn run(mut self, mut rx: watch::Receiver<bool>) {
+        loop {
+            let res = tokio::select! {
+                res = self.updates.recv() => res,
+                _ = rx.changed() => return,
+            };
+
+            let upd = match res {
+                Ok(v) => v,
+                Err(e) => match e {
+                    tokio::sync::broadcast::error::RecvError::Closed => return,
+                    tokio::sync::broadcast::error::RecvError::Lagged(missed) => {
+                        // We missed updates so the banks can't be trusted anymore,
+                        // rebuild them from the cache and let everyone else know
+                        tracing::debug!(missed, "update channel lagged, rebuilding banks");
+                        self.rebuild();
+                        let _ = self.resync_tx.send(Update::Resync);
+                        continue;
+                    }
+                },
+            };
+
+            let (k, value) = match upd {
+                Update::Resync => {
+                    // Sent by the polling task after a reconnect, or by us after a lag.
+                    // Only rebuild if the cache moved on since the banks were last built.
+                    if self.generation.load(Ordering::Acquire) != self.rebuilt_at {
+                        self.rebuild();
+                    }
+                    continue;
+                }
+                upd => match upd.any() {
+                    Some(v) => v,
+                    None => continue,
+                },
+            };
+
+            if let Ok(tk) = KeyType::try_from(&k) {
+                let segments: Vec<&str> = k.segments().collect();
+                let bank = match tk {
+                    KeyType::InputBank(index) => self.input_banks.get_mut(&index),
+                    KeyType::OutputBank(index) => self.output_banks.get_mut(&index),
+                    _ => None,
+                };
+
+                if let Some(mut b) = bank {
+                    if let Err(e) = b.update(&segments[3..], &value) {
+                        tracing::warn!(key = %k, error = %e, "could not map update onto bank");
+                        let _ =
+                            self.diagnostics
+                                .send(Diagnostic::new(k.as_str(), value.encode(), e));
+                    }
+                }
+            }
+        }
+    }
+
+    fn rebuild(&mut self) {
+        // Read before rebuilding, anything ingested while we build bumps it again
+        self.rebuilt_at = self.generation.load(Ordering::Acquire);
+        Device::resync_banks(
+            &self.cache,
+            &self.input_banks,
+            &self.output_banks,
+            &self.diagnostics,
+        );
+    }
 }
 
 impl SerializeImpl for Device {
@@ -54,11 +357,11 @@ impl SerializeImpl for Device {
         S: serde::Serializer,
     {
         let mut state = serializer.serialize_struct("Device", 5)?;
-        state.serialize_field("name", &self.name)?;
-        state.serialize_field("hostname", &self.hostname)?;
-        state.serialize_field("port", &self.port)?;
-        state.serialize_field("uid", &self.uid)?;
-        state.serialize_field("device_type", &self.device_type)?;
+        state.serialize_field("name", &self.inner.name)?;
+        state.serialize_field("hostname", &self.inner.hostname)?;
+        state.serialize_field("port", &self.inner.port)?;
+        state.serialize_field("uid", &self.inner.uid)?;
+        state.serialize_field("device_type", &self.inner.device_type)?;
         state.end()
     }
 }
@@ -76,10 +379,187 @@ pub enum DeviceType {
     Unknown,
 }
 
+/// Lifecycle of the connection between a `Device` and the hardware.
+#[derive(Debug, Clone)]
+pub enum ConnectionState {
+    /// Not connected, either `connect` hasn't been called or we gave up reconnecting
+    Disconnected,
+    /// Waiting for the device to answer the initial health check and cache pass
+    Connecting,
+    /// Long polling is running and the cache is up to date
+    Connected,
+    /// The device came back after an outage and we are waiting for a full resync
+    Resyncing,
+    /// Polling failed and we are retrying, holds the last error
+    Degraded(Arc<DeviceError>),
+}
+
+impl ConnectionState {
+    pub fn is_connected(&self) -> bool {
+        matches!(self, ConnectionState::Connected)
+    }
+}
+
+impl PartialEq for ConnectionState {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ConnectionState::Degraded(a), ConnectionState::Degraded(b)) => {
+                a.to_string() == b.to_string()
+            }
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+
+impl Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionState::Disconnected => write!(f, "Disconnected"),
+            ConnectionState::Connecting => write!(f, "Connecting"),
+            ConnectionState::Connected => write!(f, "Connected"),
+            ConnectionState::Resyncing => write!(f, "Resyncing"),
+            ConnectionState::Degraded(e) => write!(f, "Degraded: {}", e),
+        }
+    }
+}
+
+/// Controls how the background connection retries once the device stops responding.
+///
+/// The delay before retry `n` is `initial * multiplier^n`, capped at `max`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    /// Delay before the first reconnection attempt
+    pub initial: Duration,
+    /// Upper bound for the delay between attempts
+    pub max: Duration,
+    /// Factor the delay grows by after every failed attempt
+    pub multiplier: f64,
+    /// Give up after this many failed attempts, `None` retries forever
+    pub max_retries: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+            max_retries: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay to wait before reconnection attempt number `attempt` (starting at 0)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .max(1.0)
+            .powi(attempt.min(i32::MAX as u32) as i32);
+        let delay = self.initial.as_secs_f64() * factor;
+        if !delay.is_finite() || delay >= self.max.as_secs_f64() {
+            return self.max;
+        }
+        Duration::from_secs_f64(delay)
+    }
+}
+
+/// How the background task asks the device for changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PollingMode {
+    /// Keep a request open until something changes, the device's own mechanism
+    #[default]
+    LongPoll,
+    /// Fetch the whole datastore every so often, for networks that drop idle connections
+    Interval(Duration),
+}
+
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum Update {
-    Internal(String, Value),
-    External(String, Value),
+    Internal(KeyPath, Value),
+    External(KeyPath, Value),
+    /// Updates were dropped or the device came back after an outage, the channel banks have
+    /// been rebuilt from the cache and consumers should re-read any state they care about
+    Resync,
+    /// An optimistic write failed and the key went back to its previous value
+    Rollback(Rollback),
+}
+
+/// How writes show up in the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteMode {
+    /// The cache is updated once the device took the write
+    #[default]
+    Confirmed,
+    /// The cache is updated and an `Update::Internal` emitted before the write is sent.
+    /// If the device fails or doesn't answer within `timeout` the keys are restored and an
+    /// `Update::Rollback` is emitted. A write that timed out may still reach the device.
+    Optimistic { timeout: Duration },
+}
+
+/// An optimistic or coalesced write that was undone
+#[derive(Debug, Clone)]
+pub struct Rollback {
+    pub key: KeyPath,
+    /// The value the write tried to set
+    pub attempted: Value,
+    /// The value the key went back to, None if it wasn't in the cache before
+    pub restored: Option<Value>,
+    pub error: Arc<DeviceError>,
+}
+
+impl PartialEq for Rollback {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+            && self.attempted == other.attempted
+            && self.restored == other.restored
+            && self.error.to_string() == other.error.to_string()
+    }
+}
+
+impl PartialOrd for Rollback {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        (
+            &self.key,
+            &self.attempted,
+            &self.restored,
+            self.error.to_string(),
+        )
+            .partial_cmp(&(
+                &other.key,
+                &other.attempted,
+                &other.restored,
+                other.error.to_string(),
+            ))
+    }
+}
+
+#[derive(Debug)]
+struct PendingWrite {
+    /// Last value the device is known to have
+    prior: Option<Value>,
+    /// The write that currently owns the key
+    generation: u64,
+}
+
+/// A datastore key that could not be decoded or mapped onto a channel bank.
+/// The key is skipped, everything else in the same response is still ingested.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub key: String,
+    /// The value as the device sent it
+    pub raw: SerdeValue,
+    pub error: Arc<DeviceError>,
+}
+
+impl Diagnostic {
+    fn new(key: &str, raw: SerdeValue, error: impl Into<DeviceError>) -> Self {
+        Diagnostic {
+            key: key.to_string(),
+            raw,
+            error: Arc::new(error.into()),
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -91,36 +571,56 @@ enum KeyType {
     NotImplemented,
 }
 
-impl KeyType {
-    fn convert_from_str(key: &str) -> Result<(Self, uriparse::URIReference), DeviceError> {
-        let m = uriparse::URIReference::try_from(key)?;
-        let k = m.path().segments();
-        if k.len() > 2 {
-            let k = match k[0].as_str() {
-                "ext" => {
-                    let index = k[2].parse::<u32>()?;
-                    match ChannelBankType::try_from(k[1].as_str())? {
-                        ChannelBankType::Input => KeyType::InputBank(index),
-                        ChannelBankType::Output => KeyType::OutputBank(index),
-                    }
-                }
-                "avb" => KeyType::AVB,
-                "mix" => KeyType::Mixer,
-                _ => KeyType::NotImplemented,
-            };
+impl TryFrom<&KeyPath> for KeyType {
+    type Error = DeviceError;
 
-            return Ok((k, m));
+    fn try_from(key: &KeyPath) -> Result<Self, Self::Error> {
+        let (root, kind) = match (key.segment(0), key.segment(1), key.segment(2)) {
+            (Some(root), Some(kind), Some(_)) => (root, kind),
+            _ => return Err(DeviceError::KeyParseError),
         };
 
-        Err(DeviceError::KeyParseError)
+        Ok(match root {
+            "ext" => {
+                let index = key.index(2).ok_or(DeviceError::KeyParseError)?;
+                match ChannelBankType::try_from(kind)? {
+                    ChannelBankType::Input => KeyType::InputBank(index),
+                    ChannelBankType::Output => KeyType::OutputBank(index),
+                }
+            }
+            "avb" => KeyType::AVB,
+            "mix" => KeyType::Mixer,
+            _ => KeyType::NotImplemented,
+        })
     }
 }
 
 impl Update {
-    pub fn any(self) -> (String, Value) {
+    /// The key and its value after the update, a rollback yields the restored value
+    pub fn any(self) -> Option<(KeyPath, Value)> {
+        match self {
+            Update::Internal(k, v) => Some((k, v)),
+            Update::External(k, v) => Some((k, v)),
+            Update::Resync => None,
+            Update::Rollback(r) => Some((r.key, r.restored?)),
+        }
+    }
+
+    pub fn key(&self) -> Option<&KeyPath> {
         match self {
-            Update::Internal(k, v) => (k, v),
-            Update::External(k, v) => (k, v),
+            Update::Internal(k, _) => Some(k),
+            Update::External(k, _) => Some(k),
+            Update::Resync => None,
+            Update::Rollback(r) => Some(&r.key),
+        }
+    }
+
+    pub fn value(&self) -> Option<&Value> {
+        match self {
+            Update::Internal(_, v) => Some(v),
+            Update::External(_, v) => Some(v),
+            Update::Resync => None,
+            Update::Rollback(r) => r.restored.as_ref(),
         }
     }
 }
@@ -151,20 +651,20 @@ impl Display for Device {
         write!(
             f,
             "Name: \"{}\"  Type: {}  Hostname: {}:{}",
-            self.name,
-            self.device_type.to_string(),
-            self.hostname,
-            self.port
+            self.inner.name,
+            self.inner.device_type.to_string(),
+            self.inner.hostname,
+            self.inner.port
         )
     }
 }
 
 impl PartialEq for Device {
     fn eq(&self, other: &Self) -> bool {
-        self.name == other.name
-            && self.hostname == other.hostname
-            && self.port == other.port
-            && self.device_type == other.device_type
+        self.inner.name == other.inner.name
+            && self.inner.hostname == other.inner.hostname
+            && self.inner.port == other.inner.port
+            && self.inner.device_type == other.inner.device_type
     }
 }
 
@@ -172,9 +672,9 @@ impl Eq for Device {}
 
 impl Hash for Device {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.hostname.hash(state);
-        self.port.hash(state);
-        self.uid.hash(state);
+        self.inner.hostname.hash(state);
+        self.inner.port.hash(state);
+        self.inner.uid.hash(state);
     }
 }
 
@@ -186,30 +686,71 @@ impl Device {
         uid: &str,
         device_type: DeviceType,
     ) -> Device {
-        let mut rng = rand::thread_rng();
+        Self::with_transport(
+            name,
+            hostname,
+            port,
+            uid,
+            device_type,
+            HttpTransport::new(hostname, port),
+        )
+    }
+
+    /// Creates a device that reaches its datastore through `transport` instead of HTTP,
+    /// see [`crate::transport::MemoryTransport`] for one that needs no network
+    pub fn with_transport(
+        name: &str,
+        hostname: &str,
+        port: u16,
+        uid: &str,
+        device_type: DeviceType,
+        transport: impl Transport + 'static,
+    ) -> Device {
+        let b = DeviceBuilder::new(hostname, port)
+            .name(name)
+            .uid(uid)
+            .device_type(device_type);
+        Self::from_builder(&b, Arc::new(transport))
+    }
+
+    /// Configures timeouts, the HTTP client, the client id or the polling mode,
+    /// see [`DeviceBuilder`]
+    pub fn builder(hostname: &str, port: u16) -> DeviceBuilder {
+        DeviceBuilder::new(hostname, port)
+    }
 
+    pub(crate) fn from_builder(b: &DeviceBuilder, transport: Arc<dyn Transport>) -> Device {
         Device {
-            name: name.to_string(),
-            hostname: hostname.to_string(),
-            port,
-            uid: uid.to_string(),
+            inner: Arc::new(DeviceInner {
+                name: b.name.clone(),
+                hostname: b.hostname.clone(),
+                port: b.port,
+                uid: b.uid.clone(),
 
-            connected: false,
+                state: Arc::new(watch::channel(ConnectionState::Disconnected).0),
 
-            url: format!("http://{}:{}/datastore", hostname, port),
-            health: format!("http://{}:{}/apiversion", hostname, port),
-            device_type,
-            client: reqwest::Client::new(),
+                device_type: b.device_type,
+                transport,
+                polling: b.polling,
+
+                backoff: Mutex::new(b.backoff),
+                write_mode: Mutex::new(WriteMode::default()),
 
-            conn_cancel: None,
+                cache: Arc::new(DashMap::new()),
+                recorder: Arc::new(RwLock::new(None)),
+                diagnostics: tokio::sync::broadcast::channel(64).0,
 
-            cache: Arc::new(DashMap::new()),
-            updates: None,
+                pending: DashMap::new(),
+                write_generation: AtomicU64::new(0),
 
-            input_banks: None,
-            output_banks: None,
+                lifecycle: tokio::sync::Mutex::new(()),
+                conn: RwLock::new(None),
+                connection_id: AtomicU64::new(0),
 
-            client_id: rng.gen::<u32>(),
+                client_id: b.client_id,
+
+                span: tracing::info_span!("device", uid = %b.uid, hostname = %b.hostname),
+            }),
         }
     }
 
@@ -219,256 +760,968 @@ impl Device {
     }
 
     pub fn name(&self) -> String {
-        self.name.clone()
+        self.inner.name.clone()
     }
 
     pub fn device_type(&self) -> DeviceType {
-        self.device_type
+        self.inner.device_type
     }
 
     pub fn hostname(&self) -> String {
-        self.hostname.clone()
+        self.inner.hostname.clone()
     }
 
     pub fn port(&self) -> u16 {
-        self.port
+        self.inner.port
+    }
+
+    /// The id this handle identifies itself with, keep it to resume with
+    /// [`DeviceBuilder::client_id`]
+    pub fn client_id(&self) -> u32 {
+        self.inner.client_id
+    }
+
+    /// Sets the reconnection policy used by the background polling task.
+    /// Only takes effect on the next call to `connect`.
+    pub fn set_backoff(&self, backoff: Backoff) {
+        *self.inner.backoff.lock().unwrap() = backoff;
+    }
+
+    pub fn backoff(&self) -> Backoff {
+        *self.inner.backoff.lock().unwrap()
+    }
+
+    /// Sets how `set`, `set_keys` and transactions update the cache, see [`WriteMode`]
+    pub fn set_write_mode(&self, mode: WriteMode) {
+        *self.inner.write_mode.lock().unwrap() = mode;
+    }
+
+    pub fn write_mode(&self) -> WriteMode {
+        *self.inner.write_mode.lock().unwrap()
+    }
+
+    /// Returns true while an optimistic write to `key` hasn't been confirmed by the device
+    pub fn is_pending(&self, key: &str) -> bool {
+        self.inner.pending.contains_key(key)
+    }
+
+    /// Keys with optimistic writes the device hasn't confirmed yet
+    pub fn pending_keys(&self) -> Vec<KeyPath> {
+        self.inner.pending.iter().map(|p| p.key().clone()).collect()
+    }
+
+    /// Returns true if the handles point at the same underlying device connection
+    pub fn same_handle(&self, other: &Device) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
     }
 
     pub fn input_banks(&self) -> Result<Arc<DashMap<u32, ChannelBank>>, DeviceError> {
         Ok(self
-            .input_banks
+            .inner
+            .conn
+            .read()
+            .unwrap()
             .as_ref()
             .ok_or(DeviceError::ChannelBanksNotInitalized)?
+            .input_banks
             .clone())
     }
 
     pub fn output_banks(&self) -> Result<Arc<DashMap<u32, ChannelBank>>, DeviceError> {
         Ok(self
-            .output_banks
+            .inner
+            .conn
+            .read()
+            .unwrap()
             .as_ref()
             .ok_or(DeviceError::ChannelBanksNotInitalized)?
+            .output_banks
             .clone())
     }
 
     pub fn updates(&self) -> Result<tokio::sync::broadcast::Receiver<Update>, DeviceError> {
-        match self.connected {
-            true => Ok(self
-                .updates
-                .as_ref()
-                .ok_or(DeviceError::NotConnected)?
-                .subscribe()),
-            false => Err(DeviceError::NotConnected),
+        Ok(self
+            .inner
+            .conn
+            .read()
+            .unwrap()
+            .as_ref()
+            .ok_or(DeviceError::NotConnected)?
+            .updates
+            .subscribe())
+    }
+
+    /// Subscribes to the updates of every key matching `pattern`, see [`Pattern`] for the syntax.
+    /// Each item carries the captured wildcard segments, e.g. the bank and channel index.
+    /// Gaps in the stream are reported as an `Update::Resync` item with no captures.
+    pub fn watch(
+        &self,
+        pattern: &str,
+    ) -> Result<impl Stream<Item = WatchUpdate> + Send + Unpin, DeviceError> {
+        let pattern: Pattern = pattern.parse()?;
+        let updates = self.updates()?;
+
+        Ok(
+            BroadcastStream::new(updates).filter_map(move |upd| match upd {
+                // Lagging means this stream has gaps, tell the consumer to re-read the cache
+                Ok(Update::Resync) | Err(_) => Some(WatchUpdate {
+                    update: Update::Resync,
+                    captures: Captures::default(),
+                }),
+                Ok(update) => {
+                    let captures = pattern.matches(update.key()?.as_str())?;
+                    Some(WatchUpdate { update, captures })
+                }
+            }),
+        )
+    }
+
+    fn update_sender(&self) -> Option<tokio::sync::broadcast::Sender<Update>> {
+        self.inner
+            .conn
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|c| c.updates.clone())
+    }
+
+    /// Current state of the connection to the device
+    pub fn connection_state(&self) -> ConnectionState {
+        self.inner.state.borrow().clone()
+    }
+
+    /// Stream of connection state changes, starting with the current state.
+    /// Intermediate states may be skipped if the consumer is slower than the changes.
+    pub fn connection_events(&self) -> WatchStream<ConnectionState> {
+        WatchStream::new(self.inner.state.subscribe())
+    }
+
+    /// Subscribes to keys that were skipped because they could not be decoded or mapped.
+    /// Works before `connect`, so failures in the initial cache pass can be caught too.
+    pub fn diagnostics(&self) -> tokio::sync::broadcast::Receiver<Diagnostic> {
+        self.inner.diagnostics.subscribe()
+    }
+
+    pub async fn connect(&self) -> Result<(), DeviceError> {
+        let _lifecycle = self.inner.lifecycle.lock().await;
+
+        let (conn, driver) = self.open().await?;
+        self.install(conn);
+
+        // A poll loop that gives up drops the connection and leaves the device
+        // `Disconnected`, ready for the next `connect`. Errors that race with `disconnect`
+        // are returned from there.
+        let task = tokio::spawn(driver.run());
+        if let Some(conn) = self.inner.conn.read().unwrap().as_ref() {
+            conn.handle.push(task);
+        }
+
+        Ok(())
+    }
+
+    /// Like `connect`, but nothing is spawned. The polling and mapping loops are returned
+    /// as a [`ConnectionDriver`] for the caller to run on whatever executor it likes.
+    ///
+    /// The device is `Connected` once this returns, with the cache and channel banks
+    /// filled from the initial poll, but no further updates arrive until the driver runs.
+    /// `disconnect` makes `run` return `Ok(())`, dropping the driver disconnects the device.
+    pub async fn connect_driven(&self) -> Result<ConnectionDriver, DeviceError> {
+        let _lifecycle = self.inner.lifecycle.lock().await;
+
+        let (conn, driver) = self.open().await?;
+        self.install(conn);
+
+        Ok(driver)
+    }
+
+    /// Makes `conn` the current connection. Has to happen before its loops start, so a first
+    /// poll that fails right away isn't overwritten by `Connected`.
+    fn install(&self, conn: Connection) {
+        *self.inner.conn.write().unwrap() = Some(conn);
+        self.inner.state.send_replace(ConnectionState::Connected);
+    }
+
+    /// Does the initial cache pass and builds the banks, leaving the loops to the caller.
+    /// Has to be called with the lifecycle lock held.
+    async fn open(&self) -> Result<(Connection, ConnectionDriver), DeviceError> {
+        if self.inner.conn.read().unwrap().is_some() {
+            return Err(DeviceError::AlreadyConnected);
+        }
+
+        self.inner.state.send_replace(ConnectionState::Connecting);
+
+        let res = self
+            .open_connection()
+            .instrument(self.inner.span.clone())
+            .await;
+        if res.is_err() {
+            self.inner.state.send_replace(ConnectionState::Disconnected);
         }
+
+        res
     }
 
-    pub async fn connect(&mut self) -> Result<(), DeviceError> {
+    async fn open_connection(&self) -> Result<(Connection, ConnectionDriver), DeviceError> {
         self.check().await?;
 
-        let (tx, mut rx) = channel(1);
-        self.conn_cancel = Some(tx);
+        let (update_tx, _) = tokio::sync::broadcast::channel(64);
+        let updates = update_tx.clone();
+
+        let generation = Arc::new(AtomicU64::new(0));
+        let mut poller = Poller {
+            transport: self.inner.transport.clone(),
+            client_id: self.inner.client_id,
+            mode: self.inner.polling,
+            etag: None,
+            cache: self.inner.cache.clone(),
+            updates: update_tx,
+            recorder: self.inner.recorder.clone(),
+            diagnostics: self.inner.diagnostics.clone(),
+            generation: generation.clone(),
+        };
 
-        let c = self.client.clone();
-        let url = self.url.clone();
-        let mut etag: Option<HeaderValue> = None;
-        let cache = self.cache.clone();
-        let client_id = self.client_id;
+        // Initial cache pass
+        poller.poll().await?;
+
+        // Subscribe before building the mappings so nothing that lands in the cache
+        // after the build is missed
+        let map_update = updates.subscribe();
+
+        let rebuilt_at = generation.load(Ordering::Acquire);
+        let (input_banks, output_banks) =
+            Self::build_banks(&self.inner.cache, &self.inner.diagnostics);
+        let (input_banks, output_banks) = (Arc::new(input_banks), Arc::new(output_banks));
+
+        let mapper = Mapper {
+            updates: map_update,
+            resync_tx: updates.clone(),
+            cache: self.inner.cache.clone(),
+            input_banks: input_banks.clone(),
+            output_banks: output_banks.clone(),
+            diagnostics: self.inner.diagnostics.clone(),
+            generation,
+            rebuilt_at,
+        };
 
-        let (cached_tx, cached_rx) = tokio::sync::oneshot::channel();
+        let id = self.inner.connection_id.fetch_add(1, Ordering::Relaxed);
+        let handle = ConnectionHandle::new();
+
+        let driver = ConnectionDriver {
+            poll: Box::pin(
+                Self::poll_loop(
+                    poller,
+                    self.backoff(),
+                    self.inner.state.clone(),
+                    handle.cancel.subscribe(),
+                )
+                .instrument(self.inner.span.clone()),
+            ),
+            map: Box::pin(
+                mapper
+                    .run(handle.cancel.subscribe())
+                    .instrument(self.inner.span.clone()),
+            ),
+            guard: DriverGuard {
+                device: Arc::downgrade(&self.inner),
+                id,
+            },
+        };
 
-        let (update_tx, mut map_update) = tokio::sync::broadcast::channel(64);
-        self.updates = Some(update_tx.clone());
+        let conn = Connection {
+            id,
+            handle,
+            updates,
+            input_banks,
+            output_banks,
+        };
 
-        // Start background long polling
-        tokio::spawn(async move {
-            // Initial cache pass
-            let res = Self::poll(&c, &url, &mut etag, client_id, &cache, &update_tx).await;
-            let _ = cached_tx.send(res);
+        Ok((conn, driver))
+    }
 
-            // Long polling
-            loop {
-                tokio::select! {
-                    // poll
-                    res = Self::poll(&c, &url, &mut etag, client_id, &cache, &update_tx) => {
-                        if let Err(e) = res {
-                            // TODO sort this error handling out
-                            println!("{:?}", e);
-                            return;
+    /// Keeps polling until cancelled, reconnecting with backoff when a poll fails.
+    /// Fails once reconnecting is given up on.
+    async fn poll_loop(
+        mut poller: Poller,
+        backoff: Backoff,
+        state: Arc<watch::Sender<ConnectionState>>,
+        mut rx: watch::Receiver<bool>,
+    ) -> Result<(), DeviceError> {
+        let mut resyncing = false;
+        // Reconnect attempts since the last poll that worked, `/apiversion` answering alone
+        // doesn't count as working
+        let mut attempt = 0;
+        loop {
+            tokio::select! {
+                // poll
+                res = poller.poll() => {
+                    match res {
+                        Ok(_) => {
+                            if resyncing {
+                                resyncing = false;
+                                attempt = 0;
+                                tracing::info!("resynced after reconnecting");
+                                state.send_replace(ConnectionState::Connected);
+                                let _ = poller.updates.send(Update::Resync);
+                            }
+
+                            if let PollingMode::Interval(every) = poller.mode {
+                                tokio::select! {
+                                    _ = tokio::time::sleep(every) => {}
+                                    _ = rx.changed() => {
+                                        state.send_replace(ConnectionState::Disconnected);
+                                        return Ok(());
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            tracing::warn!(error = %e, "poll failed, reconnecting");
+                            state.send_replace(ConnectionState::Degraded(Arc::new(e)));
+
+                            if let Err(e) =
+                                Self::reconnect(&*poller.transport, &backoff, &mut attempt, &state, &mut rx)
+                                    .await
+                            {
+                                return match e {
+                                    DeviceError::NotConnected => {
+                                        state.send_replace(ConnectionState::Disconnected);
+                                        Ok(())
+                                    }
+                                    e => {
+                                        tracing::error!(error = %e, "giving up on the device");
+                                        Err(e)
+                                    }
+                                };
+                            }
+
+                            // Drop the etag so the first response after reconnecting is a full
+                            // resync, the device may have changed anything while we were gone
+                            poller.etag = None;
+                            resyncing = true;
+                            state.send_replace(ConnectionState::Resyncing);
                         }
                     }
+                }
 
-                    // exit if we cancel
-                    _ = rx.recv() => {
-                        return;
-                    }
+                // exit if we cancel
+                _ = rx.changed() => {
+                    state.send_replace(ConnectionState::Disconnected);
+                    return Ok(());
                 }
             }
-        });
+        }
+    }
+
+    /// Records every poll response and accepted `PATCH` from now on, replacing any
+    /// previous recorder. See [`Session`] for replaying the result.
+    ///
+    /// When already connected the session starts with the whole cache as its first poll,
+    /// so a replay has the same initial state to build banks from.
+    pub fn start_recording(&self, recorder: Recorder) {
+        let mut current = self.inner.recorder.write().unwrap();
+
+        if self.inner.conn.read().unwrap().is_some() {
+            let body = self
+                .inner
+                .cache
+                .iter()
+                .map(|e| (e.key().to_string(), e.value().encode()))
+                .collect();
+            recorder.record(SessionEventKind::Poll { etag: None, body });
+        }
 
-        self.connected = true;
+        *current = Some(Arc::new(recorder));
+    }
 
-        // Build mappings once we ready
-        match cached_rx.await? {
-            Ok(_) => {
-                self.input_banks = Some(Arc::new(extchannel::build("ibank", self.cache.clone())?));
-                self.output_banks = Some(Arc::new(extchannel::build("obank", self.cache.clone())?));
-            }
-            Err(e) => return Err(e),
+    /// Stops recording, returns once everything recorded so far is written
+    pub fn stop_recording(&self) {
+        *self.inner.recorder.write().unwrap() = None;
+    }
+
+    /// Feeds a recorded session into the device instead of connecting to the network.
+    ///
+    /// The first poll of the session becomes the initial cache pass, after which the device
+    /// behaves as if connected: banks are built and every later event is emitted as an
+    /// `Update`. Returns once the whole session has been fed, at which point the channel
+    /// banks match the recorded state. Call `disconnect` before connecting for real.
+    pub async fn replay(&self, session: &Session, pace: ReplayPace) -> Result<(), DeviceError> {
+        let lifecycle = self.inner.lifecycle.lock().await;
+
+        if self.inner.conn.read().unwrap().is_some() {
+            return Err(DeviceError::AlreadyConnected);
+        }
+
+        let mut events = session.events.iter();
+        let first = match events.next() {
+            Some(e) => match &e.kind {
+                SessionEventKind::Poll { body, .. } => (e.at(), body),
+                _ => return Err(SessionError::NoInitialPoll.into()),
+            },
+            None => return Err(SessionError::NoInitialPoll.into()),
         };
 
-        let update_input_bank = self.input_banks.clone().unwrap();
-        let update_output_bank = self.input_banks.clone().unwrap();
-
-        // Listen to updates and map that to our internal representations
-        tokio::spawn(async move {
-            loop {
-                let upd = match map_update.recv().await {
-                    Ok(v) => v,
-                    Err(e) => match e {
-                        tokio::sync::broadcast::error::RecvError::Closed => return,
-                        tokio::sync::broadcast::error::RecvError::Lagged(_) => continue,
-                    },
-                };
+        self.inner.state.send_replace(ConnectionState::Connecting);
 
-                let (k, value) = upd.any();
+        let (updates, _) = tokio::sync::broadcast::channel(64);
+        let cache = self.inner.cache.clone();
 
-                if let Ok((tk, uri)) = KeyType::convert_from_str(k.as_str()) {
-                    let k = uri.path().segments();
-                    match tk {
-                        KeyType::InputBank(index) => {
-                            match update_input_bank.get_mut(&index) {
-                                Some(mut v) => {
-                                    v.update(&k[3..], &value).unwrap();
-                                }
-                                None => {}
-                            };
-                        }
-                        KeyType::OutputBank(index) => {
-                            match update_output_bank.get_mut(&index) {
-                                Some(mut v) => {
-                                    v.update(&k[3..], &value).unwrap();
-                                }
-                                None => {}
-                            };
+        let diagnostics = &self.inner.diagnostics;
+        Self::ingest(first.1.clone(), &cache, &updates, diagnostics);
+
+        let map_update = updates.subscribe();
+        let (input_banks, output_banks) = Self::build_banks(&cache, diagnostics);
+        let (input_banks, output_banks) = (Arc::new(input_banks), Arc::new(output_banks));
+
+        let mapper = Mapper {
+            updates: map_update,
+            resync_tx: updates.clone(),
+            cache: cache.clone(),
+            input_banks: input_banks.clone(),
+            output_banks: output_banks.clone(),
+            diagnostics: diagnostics.clone(),
+            // Nothing bumps it while replaying, lags still rebuild
+            generation: Arc::new(AtomicU64::new(0)),
+            rebuilt_at: 0,
+        };
+
+        let conn = ConnectionHandle::new();
+        let mut cancel = conn.cancel.subscribe();
+        let map = mapper.run(conn.cancel.subscribe());
+        conn.push(tokio::spawn(async move {
+            map.await;
+            Ok(())
+        }));
+
+        *self.inner.conn.write().unwrap() = Some(Connection {
+            id: self.inner.connection_id.fetch_add(1, Ordering::Relaxed),
+            handle: conn,
+            updates: updates.clone(),
+            input_banks: input_banks.clone(),
+            output_banks: output_banks.clone(),
+        });
+        self.inner.state.send_replace(ConnectionState::Connected);
+
+        // Let other handles disconnect while we are feeding
+        drop(lifecycle);
+
+        let start = Instant::now();
+        for e in events {
+            if pace == ReplayPace::Recorded {
+                let at = start + e.at().saturating_sub(first.0);
+                tokio::select! {
+                    _ = tokio::time::sleep_until(at) => {}
+                    _ = cancel.changed() => return Ok(()),
+                }
+            } else if *cancel.borrow() {
+                return Ok(());
+            }
+
+            match &e.kind {
+                SessionEventKind::Poll { body, .. } => {
+                    Self::ingest(body.clone(), &cache, &updates, diagnostics)
+                }
+                SessionEventKind::Patch { body } => {
+                    for (k, raw) in body.iter() {
+                        if let Some((k, v)) = Self::decode(k, raw, diagnostics) {
+                            cache.insert(k.clone(), v.clone());
+                            let _ = updates.send(Update::Internal(k, v));
                         }
-                        _ => {}
                     }
                 }
             }
-        });
+        }
 
+        // The mapping task may still be catching up, make sure the banks match the cache
+        Self::resync_banks(&cache, &input_banks, &output_banks, diagnostics);
         Ok(())
     }
 
-    pub fn get(&self) -> Arc<DashMap<String, Value>> {
-        self.cache.clone()
+    /// Stops the background polling and mapping tasks and closes the update broadcast.
+    /// The cache keeps its last known values and `connect` can be called again afterwards.
+    pub async fn disconnect(&self) -> Result<(), DeviceError> {
+        let _lifecycle = self.inner.lifecycle.lock().await;
+
+        let conn = self
+            .inner
+            .conn
+            .write()
+            .unwrap()
+            .take()
+            .ok_or(DeviceError::NotConnected)?;
+
+        let res = conn.handle.shutdown().await;
+        self.inner.state.send_replace(ConnectionState::Disconnected);
+
+        res
+    }
+
+    /// Drops connection `id` once its driver is gone, unless `disconnect` got to it first.
+    /// Doesn't take the lifecycle lock, `disconnect` may be waiting on the very task calling this.
+    fn connection_lost(&self, id: u64) {
+        let conn = {
+            let mut conn = self.inner.conn.write().unwrap();
+            match conn.as_ref() {
+                Some(c) if c.id == id => conn.take(),
+                _ => None,
+            }
+        };
+
+        if conn.is_some() {
+            self.inner.state.send_replace(ConnectionState::Disconnected);
+        }
+    }
+
+    /// Builds the channel banks from the cache, keys that don't parse are reported and skipped
+    fn build_banks(
+        cache: &Arc<DashMap<KeyPath, Value>>,
+        diagnostics: &Diagnostics,
+    ) -> (Banks, Banks) {
+        let report = |k: &KeyPath, v: &Value, e: ParseError| {
+            tracing::warn!(key = %k, error = %e, "skipping key while building banks");
+            let _ = diagnostics.send(Diagnostic::new(k.as_str(), v.encode(), e));
+        };
+
+        let banks = (
+            extchannel::build_lenient("ibank", cache.clone(), report),
+            extchannel::build_lenient("obank", cache.clone(), report),
+        );
+
+        tracing::debug!(
+            inputs = banks.0.len(),
+            outputs = banks.1.len(),
+            "built channel banks"
+        );
+
+        banks
+    }
+
+    /// Rebuilds the channel banks from the cache in place so existing handles see the result
+    fn resync_banks(
+        cache: &Arc<DashMap<KeyPath, Value>>,
+        input_banks: &Banks,
+        output_banks: &Banks,
+        diagnostics: &Diagnostics,
+    ) {
+        let (i, o) = Self::build_banks(cache, diagnostics);
+
+        for (target, fresh) in [(input_banks, i), (output_banks, o)] {
+            target.retain(|k, _| fresh.contains_key(k));
+            for (k, v) in fresh.into_iter() {
+                target.insert(k, v);
+            }
+        }
+    }
+
+    pub fn get(&self) -> Arc<DashMap<KeyPath, Value>> {
+        self.inner.cache.clone()
     }
 
     //fn mapped_updates() {}
 
     /// Simple method to search for a key, basiclaly .contains() helper for the backing map
-    pub fn find_key(&self, key: &str) -> Vec<(String, Value)> {
-        self.cache
+    pub fn find_key(&self, key: &str) -> Vec<(KeyPath, Value)> {
+        self.inner
+            .cache
             .iter()
-            .filter(|f| f.key().contains(key))
+            .filter(|f| f.key().as_str().contains(key))
             .map(|vk| (vk.key().clone(), vk.value().clone()))
             .collect()
     }
 
+    /// What the device answers on `/apiversion`
+    pub async fn api_version(&self) -> Result<String, DeviceError> {
+        self.inner.transport.api_version().await
+    }
+
     async fn check(&self) -> Result<(), DeviceError> {
-        match self
-            .client
-            .get(&self.health)
-            .send()
-            .await?
-            .error_for_status()
-        {
-            Ok(_) => Ok(()),
-            Err(_) => Err(DeviceError::CouldNotConnect(self.url.to_string())),
+        self.inner.transport.api_version().await.map(|_| ())
+    }
+
+    /// Waits out the backoff schedule until the device answers on `/apiversion` again,
+    /// counting every try in `attempt`, successful or not.
+    /// Returns an error if we run out of retries or the connection is cancelled.
+    async fn reconnect(
+        t: &dyn Transport,
+        backoff: &Backoff,
+        attempt: &mut u32,
+        state: &watch::Sender<ConnectionState>,
+        cancel: &mut watch::Receiver<bool>,
+    ) -> Result<(), DeviceError> {
+        loop {
+            if let Some(max) = backoff.max_retries {
+                if *attempt >= max {
+                    return Err(DeviceError::CouldNotConnect(format!(
+                        "gave up after {} attempts",
+                        attempt
+                    )));
+                }
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(backoff.delay(*attempt)) => {}
+                _ = cancel.changed() => return Err(DeviceError::NotConnected),
+            }
+
+            let res = t.api_version().await;
+            *attempt += 1;
+
+            match res {
+                Ok(_) => {
+                    tracing::info!(attempt = *attempt, "device is reachable again");
+                    return Ok(());
+                }
+                Err(e) => {
+                    tracing::debug!(attempt = *attempt, error = %e, "reconnect attempt failed");
+                    state.send_replace(ConnectionState::Degraded(Arc::new(e)));
+                }
+            }
         }
     }
 
-    async fn poll(
-        c: &reqwest::Client,
-        url: &str,
-        etag: &mut Option<HeaderValue>,
-        client_id: u32,
-        cache: &Arc<DashMap<String, Value>>,
+    /// Decodes a datastore response into the cache and announces every key as an update.
+    /// Keys that fail to decode are reported as diagnostics and left out.
+    fn ingest(
+        m: HashMap<String, SerdeValue>,
+        cache: &Arc<DashMap<KeyPath, Value>>,
         updates: &tokio::sync::broadcast::Sender<Update>,
-    ) -> Result<(), DeviceError> {
-        // Check if we are long polling
-        // If we are long polling, send the etag header we stored
-        // If not just ask for new data
+        diagnostics: &Diagnostics,
+    ) {
+        for (k, raw) in m.into_iter() {
+            if let Some((k, v)) = Self::decode(&k, &raw, diagnostics) {
+                cache.insert(k.clone(), v.clone());
+                let _ = updates.send(Update::External(k, v));
+            }
+        }
+    }
+
+    fn decode(key: &str, raw: &SerdeValue, diagnostics: &Diagnostics) -> Option<(KeyPath, Value)> {
+        let res = key
+            .parse::<KeyPath>()
+            .map_err(DeviceError::from)
+            .and_then(|k| {
+                let v = Value::try_from(raw.clone()).and_then(|v| v.decode(key))?;
+                Ok((k, v))
+            });
+
+        match res {
+            Ok(v) => Some(v),
+            Err(e) => {
+                tracing::warn!(key, raw = %raw, error = %e, "could not decode value");
+                let _ = diagnostics.send(Diagnostic::new(key, raw.clone(), e));
+                None
+            }
+        }
+    }
+
+    pub async fn set(&self, r: crate::Request) -> Result<(), DeviceError> {
+        self.patch(vec![(r.key, r.val)]).await
+    }
 
-        let c = c.get(url).query(&[("client", client_id)]);
+    /// Writes a key and waits for the device to settle on a value, which is returned.
+    ///
+    /// The device clamps some writes (a trim outside its range) and takes a while to apply
+    /// others (sample rate, smux), so the key is read back until it holds the written value,
+    /// or holds the same other value twice in a row. A first read back that still holds the
+    /// value from before the write is taken as clamped to it and returned right away. If
+    /// nothing settles within `timeout` the last value read is returned. The cache is
+    /// updated with whatever the device settled on.
+    pub async fn set_confirmed(
+        &self,
+        r: crate::Request,
+        timeout: Duration,
+    ) -> Result<Value, DeviceError> {
+        let prior = self.get_value(r.key.as_str());
+        let mut updates = self.updates().ok();
+
+        self.patch(vec![(r.key.clone(), r.val.clone())]).await?;
+
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+        let mut interval = tokio::time::interval(CONFIRM_INTERVAL);
+        let mut last: Option<Value> = None;
+
+        let settled = loop {
+            let seen = tokio::select! {
+                _ = &mut deadline => break last.ok_or(DeviceError::Timeout)?,
+                _ = interval.tick() => self.read(&r.key).await?,
+                Some(v) = Self::next_external(&mut updates, &r.key) => Some(v),
+            };
 
-        let q = match &etag {
-            Some(v) => c.header("If-None-Match", v).send().await?,
-            None => c.send().await?,
+            match seen {
+                Some(v) if v == r.val => break v,
+                // Clamped back to where it was, it isn't going to change anymore
+                Some(v) if last.is_none() && Some(&v) == prior.as_ref() => break v,
+                Some(v) if Some(&v) == last.as_ref() && Some(&v) != prior.as_ref() => break v,
+                Some(v) => last = Some(v),
+                None => {}
+            }
         };
 
-        // Return early if the content hasn't been modified
-        if q.status() == 304 {
-            return Ok(());
+        if settled != r.val {
+            self.inner.cache.insert(r.key.clone(), settled.clone());
+            if let Some(upd) = self.update_sender() {
+                let _ = upd.send(Update::External(r.key, settled.clone()));
+            }
         }
 
-        // Store the "etag" value which we use to only get updates
-        *etag = q.headers().get(reqwest::header::ETAG).cloned();
+        Ok(settled)
+    }
 
-        // Map into hashmap
-        let m = q.json::<HashMap<String, SerdeValue>>().await?;
+    /// Reads a single key from the device, bypassing the cache
+    async fn read(&self, key: &KeyPath) -> Result<Option<Value>, DeviceError> {
+        let raw = self
+            .inner
+            .transport
+            .read(self.inner.client_id, key.as_str())
+            .await?;
 
-        let c = cache.clone();
+        match raw {
+            Some(raw) => Ok(Some(Value::try_from(raw)?.decode(key.as_str())?)),
+            None => Ok(None),
+        }
+    }
 
-        for item in m.into_iter() {
-            let v = Value::try_from(item.1)?.decode(&item.0)?;
-            c.insert(item.0.clone(), v.clone());
-            let _ = updates.send(Update::External(item.0, v));
+    /// Waits for the device to report a change to `key`, None if there are no updates
+    async fn next_external(
+        updates: &mut Option<tokio::sync::broadcast::Receiver<Update>>,
+        key: &KeyPath,
+    ) -> Option<Value> {
+        let updates = updates.as_mut()?;
+        loop {
+            match updates.recv().await {
+                Ok(Update::External(k, v)) if k == *key => return Some(v),
+                Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
         }
+    }
 
-        Ok(())
+    /// Writes several keys in one `PATCH`. Nothing is sent if any of them is not a valid key,
+    /// read only or doesn't fit its description in [`Schema::motu`].
+    pub async fn set_keys<K: AsRef<str>>(&self, data: &[(K, Value)]) -> Result<(), DeviceError> {
+        let values = data
+            .iter()
+            .map(|(key, val)| Ok((key.as_ref().parse()?, val.clone())))
+            .collect::<Result<Vec<(KeyPath, Value)>, DeviceError>>()?;
+
+        self.patch(values).await
     }
 
-    pub async fn set(&self, r: crate::Request) -> Result<(), DeviceError> {
-        self.set_keys(&[(r.key.as_str(), r.val)]).await
+    /// Starts a [`Transaction`], which batches writes from several places into one `PATCH`
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.clone())
     }
 
-    pub async fn set_keys(&self, data: &[(&str, Value)]) -> Result<(), DeviceError> {
-        let mut m = HashMap::new();
+    /// Captures the whole cache together with the uid, model and API version of the device.
+    /// Fails with `NotConnected` if nothing has been polled yet.
+    pub async fn snapshot(&self) -> Result<Snapshot, DeviceError> {
+        Snapshot::take(self).await
+    }
+
+    /// Writes back the keys of `snapshot` that pass `filter`, are writable and differ from
+    /// what the device holds now, in as few `PATCH`es as possible. Batches sent before a
+    /// failed one stay written.
+    ///
+    /// Fails before writing anything if an entry can't be decoded or doesn't fit the
+    /// schema, or with `SnapshotMismatch` if the snapshot was taken from a device with a
+    /// different uid or model, see `restore_unchecked` to allow that.
+    ///
+    /// ```no_run
+    /// # async fn rollback(d: motu_avb_api::Device) -> Result<(), Box<dyn std::error::Error>> {
+    /// use motu_avb_api::Snapshot;
+    ///
+    /// let known_good = Snapshot::load("rig.toml")?;
+    /// let report = d
+    ///     .restore(&known_good, |k| k.as_str().starts_with("ext/obank"))
+    ///     .await?;
+    /// println!("{} keys written", report.written.len());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn restore(
+        &self,
+        snapshot: &Snapshot,
+        filter: impl FnMut(&KeyPath) -> bool,
+    ) -> Result<RestoreReport, DeviceError> {
+        snapshot.restore(self, false, filter).await
+    }
+
+    /// Like `restore`, but takes snapshots of any device, e.g. to copy a configuration to
+    /// a second unit. Keys this device doesn't have end up in the report's `missing`.
+    pub async fn restore_unchecked(
+        &self,
+        snapshot: &Snapshot,
+        filter: impl FnMut(&KeyPath) -> bool,
+    ) -> Result<RestoreReport, DeviceError> {
+        snapshot.restore(self, true, filter).await
+    }
 
-        for (key, val) in data.iter() {
-            m.insert(key.to_string(), val.clone());
+    /// Starts a [`Coalescer`] for controls that change faster than the device should be
+    /// written to, `PATCH`es are sent at most once every `min_interval`.
+    /// Has to be called from within a tokio runtime.
+    pub fn coalescer(&self, min_interval: Duration) -> Coalescer {
+        Coalescer::new(self.clone(), min_interval)
+    }
+
+    pub(crate) async fn patch(&self, values: Vec<(KeyPath, Value)>) -> Result<(), DeviceError> {
+        if let WriteMode::Optimistic { timeout } = self.write_mode() {
+            return self.patch_optimistic(values, timeout).await;
         }
 
-        let form = reqwest::multipart::Form::new().text("json", serde_json::to_string(&m)?);
+        self.send(&values).await?;
 
-        let res = self
-            .client
-            .patch(&self.url)
-            .query(&[("client", self.client_id)])
-            .multipart(form)
-            .send()
-            .await?;
+        // Update our internal cache
+        for (key, val) in values.into_iter() {
+            self.apply_local(key, val);
+        }
 
-        match res.status() {
-            StatusCode::OK | StatusCode::NO_CONTENT => {
-                // Update our internal cache
-                for (key, val) in data.into_iter() {
-                    self.cache.insert(key.to_string(), val.clone());
-                    if let Some(upd) = &self.updates {
-                        upd.send(Update::Internal(key.to_string(), val.clone()))?;
-                    }
+        Ok(())
+    }
+
+    async fn patch_optimistic(
+        &self,
+        values: Vec<(KeyPath, Value)>,
+        timeout: Duration,
+    ) -> Result<(), DeviceError> {
+        // Nothing is applied if the schema rejects the write
+        for (key, val) in values.iter() {
+            Schema::motu().validate(key.as_str(), val)?;
+        }
+
+        let generation = self.inner.write_generation.fetch_add(1, Ordering::Relaxed);
+        for (key, val) in values.iter() {
+            // An earlier write that is still pending already holds the last confirmed value
+            let prior = self.inner.cache.get(key).map(|v| v.value().clone());
+            self.inner
+                .pending
+                .entry(key.clone())
+                .and_modify(|p| p.generation = generation)
+                .or_insert(PendingWrite { prior, generation });
+
+            self.apply_local(key.clone(), val.clone());
+        }
+
+        let res = match tokio::time::timeout(timeout, self.send(&values)).await {
+            Ok(res) => res,
+            Err(_) => Err(DeviceError::Timeout),
+        };
+
+        match res {
+            Ok(_) => {
+                for (key, val) in values.into_iter() {
+                    self.confirm(key, val, generation);
                 }
                 Ok(())
             }
-            _ => Err(DeviceError::BadResponse(
-                res.status().clone(),
-                res.text().await?,
-            )),
+            Err(e) => {
+                let e = Arc::new(e);
+                for (key, val) in values.into_iter() {
+                    self.rollback(key, val, generation, e.clone());
+                }
+                Err(DeviceError::RolledBack(e))
+            }
+        }
+    }
+
+    fn confirm(&self, key: KeyPath, val: Value, generation: u64) {
+        let pending = &self.inner.pending;
+        if pending
+            .remove_if(&key, |_, p| p.generation == generation)
+            .is_none()
+        {
+            // A later write owns the key, if that one fails it should go back to this value
+            if let Some(mut p) = pending.get_mut(&key) {
+                p.prior = Some(val);
+            }
+        }
+    }
+
+    fn rollback(&self, key: KeyPath, attempted: Value, generation: u64, error: Arc<DeviceError>) {
+        let restored = match self
+            .inner
+            .pending
+            .remove_if(&key, |_, p| p.generation == generation)
+        {
+            Some((_, p)) => p.prior,
+            // A later write owns the key and rolls back on its own
+            None => return,
+        };
+
+        self.undo_local(key, attempted, restored, error);
+    }
+
+    /// Puts `restored` back into the cache where `attempted` was applied locally and
+    /// announces it as an `Update::Rollback`
+    pub(crate) fn undo_local(
+        &self,
+        key: KeyPath,
+        attempted: Value,
+        restored: Option<Value>,
+        error: Arc<DeviceError>,
+    ) {
+        // The device changed the key while the write was pending, that is newer than
+        // anything we could put back
+        match self.inner.cache.get(&key) {
+            Some(v) if *v == attempted => {}
+            _ => return,
+        }
+
+        match &restored {
+            Some(v) => self.inner.cache.insert(key.clone(), v.clone()),
+            None => self.inner.cache.remove(&key).map(|(_, v)| v),
+        };
+
+        if let Some(upd) = self.update_sender() {
+            let _ = upd.send(Update::Rollback(Rollback {
+                key,
+                attempted,
+                restored,
+                error,
+            }));
+        }
+    }
+
+    /// Validates and sends a `PATCH` without touching the cache
+    pub(crate) async fn send(&self, values: &[(KeyPath, Value)]) -> Result<(), DeviceError> {
+        let mut m = HashMap::new();
+
+        for (key, val) in values.iter() {
+            Schema::motu().validate(key.as_str(), val)?;
+            m.insert(key.to_string(), val.encode());
+        }
+
+        let start = Instant::now();
+        let res = self
+            .inner
+            .transport
+            .patch(self.inner.client_id, m.clone())
+            .instrument(self.inner.span.clone())
+            .await;
+
+        self.inner.span.in_scope(|| match &res {
+            Ok(_) => tracing::debug!(keys = m.len(), elapsed = ?start.elapsed(), "patch"),
+            Err(e) => tracing::warn!(keys = m.len(), error = %e, "patch failed"),
+        });
+        res?;
+
+        if let Some(r) = self.inner.recorder.read().unwrap().as_ref() {
+            r.record(SessionEventKind::Patch { body: m });
+        }
+
+        Ok(())
+    }
+
+    /// Puts a write into the cache and announces it as an `Update::Internal`, if anyone
+    /// is listening
+    pub(crate) fn apply_local(&self, key: KeyPath, val: Value) {
+        self.inner.cache.insert(key.clone(), val.clone());
+        if let Some(upd) = self.update_sender() {
+            let _ = upd.send(Update::Internal(key, val));
         }
     }
 
     pub fn get_value(&self, key: &str) -> Option<Value> {
-        match self.cache.get(key) {
+        match self.inner.cache.get(key) {
             Some(v) => Some(v.value().clone()),
             None => None,
         }
@@ -476,7 +1729,7 @@ impl Device {
 
     pub fn uid(&self) -> &str {
         //self.get_value("uid").map(Into::into)
-        &self.uid
+        &self.inner.uid
     }
 }
 
@@ -490,6 +1743,8 @@ pub enum DeviceError {
     CouldNotConnect(String),
     #[error("no connected to device yet, run connect?")]
     NotConnected,
+    #[error("device is already connected, run disconnect first?")]
+    AlreadyConnected,
     #[error(transparent)]
     ValueParsingError(#[from] ValueError),
     #[error(transparent)]
@@ -497,6 +1752,8 @@ pub enum DeviceError {
     #[error("unexpected response from device: `{0}`: `{1}`")]
     BadResponse(StatusCode, String),
     #[error(transparent)]
+    TaskError(#[from] tokio::task::JoinError),
+    #[error(transparent)]
     OneShotRecvError(#[from] tokio::sync::oneshot::error::RecvError),
     #[error("channel banks have not been built yet, did you run connect?")]
     ChannelBanksNotInitalized,
@@ -505,7 +1762,21 @@ pub enum DeviceError {
     #[error(transparent)]
     BroadcastError(#[from] tokio::sync::broadcast::error::SendError<Update>),
     #[error(transparent)]
-    URIParseError(#[from] uriparse::URIReferenceError),
+    PatternError(#[from] PatternError),
+    #[error(transparent)]
+    SessionError(#[from] SessionError),
+    #[error(transparent)]
+    SchemaError(#[from] SchemaError),
+    #[error(transparent)]
+    KeyPathError(#[from] KeyPathError),
     #[error(transparent)]
     ParseIntError(#[from] std::num::ParseIntError),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error("device did not answer in time")]
+    Timeout,
+    #[error("write was rolled back: {0}")]
+    RolledBack(Arc<DeviceError>),
+    #[error("snapshot was taken from `{0}`, not from this device `{1}`")]
+    SnapshotMismatch(String, String),
 }
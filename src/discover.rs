@@ -18,12 +18,21 @@ pub async fn from_name(name: &str, timeout: Option<Duration>) -> Result<Device,
         None => Duration::from_secs(10),
     };
 
+    tracing::debug!(name, ?timeout, "looking for device");
+
     let mut browser = async_zeroconf::ServiceBrowserBuilder::new("_http._tcp");
     let mut services = browser.timeout(timeout).browse()?;
 
     while let Some(Ok(v)) = services.recv().await {
+        tracing::trace!(service = v.name(), "found service");
         if v.name() == name {
             let resolved_service = async_zeroconf::ServiceResolver::r(&v).await?;
+            tracing::debug!(
+                service = resolved_service.name(),
+                host = ?resolved_service.host(),
+                port = resolved_service.port(),
+                "resolved service"
+            );
 
             return new_from_mdns(&resolved_service);
         }
@@ -40,6 +49,8 @@ pub async fn discover(timeout: Option<Duration>) -> Result<Vec<Device>, Discover
         None => Duration::from_secs(10),
     };
 
+    tracing::debug!(?timeout, "discovering devices");
+
     let mut browser = async_zeroconf::ServiceBrowserBuilder::new("_http._tcp");
     let mut services = browser.timeout(timeout).browse()?;
 
@@ -47,7 +58,14 @@ pub async fn discover(timeout: Option<Duration>) -> Result<Vec<Device>, Discover
     let mut found: HashSet<String> = HashSet::new();
 
     while let Some(Ok(v)) = services.recv().await {
+        tracing::trace!(service = v.name(), "found service");
         let resolved_service = async_zeroconf::ServiceResolver::r(&v).await?;
+        tracing::debug!(
+            service = resolved_service.name(),
+            host = ?resolved_service.host(),
+            port = resolved_service.port(),
+            "resolved service"
+        );
 
         match resolved_service
             .txt()
@@ -58,6 +76,7 @@ pub async fn discover(timeout: Option<Duration>) -> Result<Vec<Device>, Discover
                 let d = std::str::from_utf8(v)?;
                 if d.contains("netiodevice") {
                     let nd = new_from_mdns(&resolved_service)?;
+                    tracing::debug!(uid = nd.uid(), hostname = %nd.hostname(), "discovered device");
                     if !found.contains(nd.uid()) {
                         found.insert(nd.uid().to_string());
                         devices.push(nd.clone());
@@ -91,11 +110,15 @@ pub async fn streaming_discover(
 
     let mut devices = Vec::new();
 
+    tracing::debug!(?timeout, "discovering devices");
+
     tokio::spawn(async move {
         while let Some(Ok(v)) = services.recv().await {
+            tracing::trace!(service = v.name(), "found service");
             let resolved_service = match async_zeroconf::ServiceResolver::r(&v).await {
                 Ok(v) => v,
                 Err(e) => {
+                    tracing::warn!(service = v.name(), error = %e, "could not resolve service");
                     tx.send(Err(DiscoveryError::ZeroconfError(e)))
                         .await
                         .unwrap();
@@ -123,11 +146,21 @@ pub async fn streaming_discover(
                         let nd = match new_from_mdns(&resolved_service) {
                             Ok(v) => v,
                             Err(e) => {
+                                tracing::warn!(
+                                    service = resolved_service.name(),
+                                    error = %e,
+                                    "not a usable device"
+                                );
                                 tx.send(Err(e)).await.unwrap();
                                 continue;
                             }
                         };
                         if devices.iter().find(|v| **v == nd).is_none() {
+                            tracing::debug!(
+                                uid = nd.uid(),
+                                hostname = %nd.hostname(),
+                                "discovered device"
+                            );
                             devices.push(nd.clone());
                             tx.send(Ok(nd)).await.unwrap();
                         }
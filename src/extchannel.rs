@@ -1,13 +1,12 @@
 use crate::value::{Value, ValueError};
-use crate::Request;
+use crate::{KeyPath, Request};
 use dashmap::DashMap;
 use std::collections::HashMap;
 use std::sync::Arc;
 use thiserror::Error;
-use uriparse::Segment;
 
 pub trait PathSeg {
-    fn seg(&self) -> String;
+    fn path(&self) -> KeyPath;
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -35,10 +34,10 @@ impl Default for ChannelBankType {
 }
 
 impl PathSeg for ChannelBankType {
-    fn seg(&self) -> String {
+    fn path(&self) -> KeyPath {
         match self {
-            ChannelBankType::Input => "ibank".to_string(),
-            ChannelBankType::Output => "obank".to_string(),
+            ChannelBankType::Input => KeyPath::literal("ibank"),
+            ChannelBankType::Output => KeyPath::literal("obank"),
         }
     }
 }
@@ -99,23 +98,16 @@ macro_rules! set_mac {
     $func_name:ident, $vy:ty) => {
         $(#[$meta])*
         pub fn $func_name(&self, index: u32, v: $vy) -> Option<Request> {
-            match self.channels.get(&index) {
-                Some(c) => add_optional_req(
-                    c.$func_name(v),
-                    Request {
-                        key: format!("{}/ch", self.seg()),
-                        val: Value::Bool(false),
-                    },
-                ),
-                None => None,
-            }
+            let c = self.channels.get(&index)?;
+            c.$func_name(v).map(|r| self.channel_request(r))
         }
     };
 }
 
 impl ChannelBank {
-    pub fn update(&mut self, key: &[Segment], value: &Value) -> Result<(), ParseError> {
-        match key[0].as_str() {
+    pub fn update(&mut self, key: &[&str], value: &Value) -> Result<(), ParseError> {
+        let first = key.first().ok_or(ParseError::NotEnoughDataInSegment)?;
+        match *first {
             "name" => self.name = Some(value.to_string()),
             "numCh" => self.num_channels = value.try_into()?,
             "maxCh" => self.max_channels = value.try_into()?,
@@ -143,7 +135,7 @@ impl ChannelBank {
     /// Generates a set channel bank name request
     pub fn set_name(&self, name: &str) -> Request {
         Request {
-            key: format!("{}/name", self.seg()),
+            key: self.path().push("name"),
             val: Value::String(name.to_string()),
         }
     }
@@ -151,7 +143,7 @@ impl ChannelBank {
     /// Generates a set channel name request
     pub fn set_channel_name(&self, index: u32, name: &str) -> Request {
         Request {
-            key: format!("{}/ch/{}/name", self.seg(), index,),
+            key: self.path().push("ch").push(index).push("name"),
             val: Value::String(name.to_string()),
         }
     }
@@ -159,15 +151,15 @@ impl ChannelBank {
     /// Generates a set channel trim request
     /// Returns None if the channel doesn't allow trim or if the trim is outside acceptable range
     pub fn set_channel_trim(&self, index: u32, trim: i32) -> Option<Request> {
-        match self.channels.get(&index) {
-            Some(c) => add_optional_req(
-                c.set_trim(trim),
-                Request {
-                    key: format!("{}/ch", self.seg()),
-                    val: Value::Bool(false),
-                },
-            ),
-            None => None,
+        let c = self.channels.get(&index)?;
+        c.set_trim(trim).map(|r| self.channel_request(r))
+    }
+
+    /// Turns a request relative to a channel into one for this bank
+    fn channel_request(&self, r: Request) -> Request {
+        Request {
+            key: self.path().push("ch").join(&r.key),
+            val: r.val,
         }
     }
 
@@ -177,8 +169,10 @@ impl ChannelBank {
 }
 
 impl PathSeg for ChannelBank {
-    fn seg(&self) -> String {
-        format!("ext/{}/{}", self.t.seg(), self.index)
+    fn path(&self) -> KeyPath {
+        KeyPath::literal("ext")
+            .join(&self.t.path())
+            .push(self.index)
     }
 }
 
@@ -278,8 +272,9 @@ pub enum Trim {
 }
 
 impl ExtChannel {
-    pub fn update(&mut self, key: &[Segment], value: &Value) -> Result<(), ParseError> {
-        match key[0].as_str() {
+    pub fn update(&mut self, key: &[&str], value: &Value) -> Result<(), ParseError> {
+        let first = key.first().ok_or(ParseError::NotEnoughDataInSegment)?;
+        match *first {
             "defaultName" => self.default_name = value.into(),
             "name" => self.name = value.into(),
             "src" => self.src = value.into(),
@@ -289,8 +284,8 @@ impl ExtChannel {
             "stereoTrimRange" => self.set_stereo_trim_range(value.try_into()?),
             "pad" => self.pad = Some(value.try_into()?),
             "phase" => self.phase = Some(value.try_into()?),
-            "48V" => self.phase = Some(value.try_into()?),
-            "connection" => self.phase = Some(value.try_into()?),
+            "48V" => self.phantom_power = Some(value.try_into()?),
+            "connection" => self.connection = Some(value.try_into()?),
             _ => {}
         }
         Ok(())
@@ -322,8 +317,8 @@ impl ExtChannel {
     pub fn set_trim(&self, trim: i32) -> Option<Request> {
         let (key, t) = match &self.trim {
             Some(v) => match v {
-                Trim::Mono(t) => (format!("{}/trim", self.index), t),
-                Trim::Stereo(t) => (format!("{}/stereoTrim", self.index), t),
+                Trim::Mono(t) => (KeyPath::from(self.index).push("trim"), t),
+                Trim::Stereo(t) => (KeyPath::from(self.index).push("stereoTrim"), t),
             },
             None => return None,
         };
@@ -338,11 +333,12 @@ impl ExtChannel {
         })
     }
 
+    // Switches are 0/1 ints in the datastore, not JSON bools
     pub fn set_pad(&self, v: bool) -> Option<Request> {
         match self.pad {
             Some(_) => Some(Request {
-                key: format!("{}/pad", self.index),
-                val: Value::Bool(v),
+                key: KeyPath::from(self.index).push("pad"),
+                val: Value::Int(v.into()),
             }),
             None => None,
         }
@@ -351,8 +347,8 @@ impl ExtChannel {
     pub fn set_phase(&self, v: bool) -> Option<Request> {
         match self.phase {
             Some(_) => Some(Request {
-                key: format!("{}/phase", self.index),
-                val: Value::Bool(v),
+                key: KeyPath::from(self.index).push("phase"),
+                val: Value::Int(v.into()),
             }),
             None => None,
         }
@@ -361,8 +357,8 @@ impl ExtChannel {
     pub fn set_phantom_power(&self, v: bool) -> Option<Request> {
         match self.phantom_power {
             Some(_) => Some(Request {
-                key: format!("{}/48V", self.index),
-                val: Value::Bool(v),
+                key: KeyPath::from(self.index).push("48V"),
+                val: Value::Int(v.into()),
             }),
             None => None,
         }
@@ -371,37 +367,58 @@ impl ExtChannel {
 
 pub fn build(
     prefix: &str,
-    cache: Arc<DashMap<String, Value>>,
+    cache: Arc<DashMap<KeyPath, Value>>,
 ) -> Result<DashMap<u32, ChannelBank>, ParseError> {
+    let mut first = None;
+    let channel_bank = build_lenient(prefix, cache, |_, _, e| {
+        first.get_or_insert(e);
+    });
+
+    match first {
+        Some(e) => Err(e),
+        None => Ok(channel_bank),
+    }
+}
+
+/// Like `build`, but keys that fail to parse are handed to `on_error` and skipped
+/// instead of failing the whole build
+pub fn build_lenient(
+    prefix: &str,
+    cache: Arc<DashMap<KeyPath, Value>>,
+    mut on_error: impl FnMut(&KeyPath, &Value, ParseError),
+) -> DashMap<u32, ChannelBank> {
     let channel_bank: DashMap<u32, ChannelBank> = DashMap::new();
 
     for item in cache.iter() {
-        let m = uriparse::URIReference::try_from(item.key() as &str)?;
-        let k = m.path().segments();
-
-        let value = item.value();
-
-        if k.len() > 2 && k[1] == prefix {
-            let index = k[2].parse::<u32>()?;
-
-            let mut b = channel_bank.entry(index).or_insert(ChannelBank {
-                index,
-                t: ChannelBankType::try_from(prefix)?,
-                ..Default::default()
-            });
-
-            b.update(&k[3..], value)?;
+        if let Err(e) = update_bank(&channel_bank, prefix, item.key(), item.value()) {
+            on_error(item.key(), item.value(), e);
         }
     }
 
-    Ok(channel_bank)
+    channel_bank
 }
 
-fn add_optional_req(l: Option<Request>, r: Request) -> Option<Request> {
-    match l {
-        Some(o) => Some(r + o),
-        None => None,
+fn update_bank(
+    channel_bank: &DashMap<u32, ChannelBank>,
+    prefix: &str,
+    key: &KeyPath,
+    value: &Value,
+) -> Result<(), ParseError> {
+    let k: Vec<&str> = key.segments().collect();
+
+    if k.len() > 2 && k[1] == prefix {
+        let index = k[2].parse::<u32>()?;
+
+        let mut b = channel_bank.entry(index).or_insert(ChannelBank {
+            index,
+            t: ChannelBankType::try_from(prefix)?,
+            ..Default::default()
+        });
+
+        b.update(&k[3..], value)?;
     }
+
+    Ok(())
 }
 
 #[derive(Error, Debug)]
@@ -418,6 +435,4 @@ pub enum ParseError {
     ParseIntError(#[from] std::num::ParseIntError),
     #[error(transparent)]
     ValueError(#[from] ValueError),
-    #[error(transparent)]
-    URIParseError(#[from] uriparse::URIReferenceError),
 }
@@ -0,0 +1,187 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Borrow;
+use std::fmt::Display;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// A `/` separated datastore key like `ext/obank/0/ch/1/trim`.
+///
+/// Always holds at least one segment and never an empty one. Hashes and borrows as the
+/// plain string, so maps keyed by `KeyPath` can be looked up with a `&str`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KeyPath {
+    path: String,
+}
+
+impl KeyPath {
+    /// A key from a literal, which is trusted to be valid
+    pub(crate) fn literal(path: &'static str) -> KeyPath {
+        debug_assert!(path.parse::<KeyPath>().is_ok(), "invalid key `{}`", path);
+        KeyPath {
+            path: path.to_string(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        self.path.split('/')
+    }
+
+    pub fn segment(&self, i: usize) -> Option<&str> {
+        self.segments().nth(i)
+    }
+
+    /// Returns segment `i` parsed as a bank, channel or mixer index
+    pub fn index(&self, i: usize) -> Option<u32> {
+        self.segment(i)?.parse().ok()
+    }
+
+    /// The key without its last segment, None for single segment keys
+    pub fn parent(&self) -> Option<KeyPath> {
+        let (parent, _) = self.path.rsplit_once('/')?;
+        Some(KeyPath {
+            path: parent.to_string(),
+        })
+    }
+
+    /// Appends one or more segments, `child` is parsed like any other key
+    pub fn child(&self, child: impl Display) -> Result<KeyPath, KeyPathError> {
+        let child: KeyPath = child.to_string().parse()?;
+        Ok(self.join(&child))
+    }
+
+    /// Appends segments that are known to be valid, like literals and indices
+    pub(crate) fn push(&self, child: impl Display) -> KeyPath {
+        KeyPath {
+            path: format!("{}/{}", self.path, child),
+        }
+    }
+
+    /// Appends another key
+    pub fn join(&self, other: &KeyPath) -> KeyPath {
+        KeyPath {
+            path: format!("{}/{}", self.path, other.path),
+        }
+    }
+
+    pub fn starts_with(&self, prefix: &KeyPath) -> bool {
+        self.strip_prefix(prefix).is_some() || self == prefix
+    }
+
+    /// The rest of the key after `prefix`, None if it doesn't start with `prefix` or
+    /// nothing is left
+    pub fn strip_prefix(&self, prefix: &KeyPath) -> Option<KeyPath> {
+        let rest = self.path.strip_prefix(&prefix.path)?.strip_prefix('/')?;
+        Some(KeyPath {
+            path: rest.to_string(),
+        })
+    }
+}
+
+impl FromStr for KeyPath {
+    type Err = KeyPathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim_matches('/');
+        if s.is_empty() {
+            return Err(KeyPathError::Empty);
+        }
+        if s.split('/').any(|v| v.is_empty()) {
+            return Err(KeyPathError::EmptySegment(s.to_string()));
+        }
+
+        Ok(KeyPath {
+            path: s.to_string(),
+        })
+    }
+}
+
+impl TryFrom<&str> for KeyPath {
+    type Error = KeyPathError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<u32> for KeyPath {
+    fn from(index: u32) -> Self {
+        KeyPath {
+            path: index.to_string(),
+        }
+    }
+}
+
+impl From<KeyPath> for String {
+    fn from(val: KeyPath) -> String {
+        val.path
+    }
+}
+
+impl Display for KeyPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path)
+    }
+}
+
+impl AsRef<str> for KeyPath {
+    fn as_ref(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Borrow<str> for KeyPath {
+    fn borrow(&self) -> &str {
+        &self.path
+    }
+}
+
+// Has to agree with the hash of the borrowed `str`
+impl Hash for KeyPath {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.path.as_str().hash(state);
+    }
+}
+
+impl PartialEq<str> for KeyPath {
+    fn eq(&self, other: &str) -> bool {
+        self.path == other
+    }
+}
+
+impl PartialEq<&str> for KeyPath {
+    fn eq(&self, other: &&str) -> bool {
+        self.path == *other
+    }
+}
+
+impl Serialize for KeyPath {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.path)
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyPath {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum KeyPathError {
+    #[error("key is empty")]
+    Empty,
+    #[error("key `{0}` contains an empty segment")]
+    EmptySegment(String),
+}
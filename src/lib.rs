@@ -2,14 +2,46 @@
 extern crate lazy_static;
 
 mod value;
-pub use value::{Value, ValueError};
+pub use value::{MEnum, RawValue, Value, ValueError};
 pub mod extchannel;
+pub mod schema;
 
 pub mod device;
-pub use device::{Device, Update};
+pub use device::{
+    Backoff, ConnectionState, Device, Diagnostic, PollingMode, Rollback, Update, WriteMode,
+};
+
+mod builder;
+pub use builder::DeviceBuilder;
+
+mod key;
+pub use key::{KeyPath, KeyPathError};
 
 mod request;
 pub use request::Request;
 
+pub mod session;
+
+mod coalesce;
+pub use coalesce::Coalescer;
+
+mod transaction;
+pub use transaction::{Transaction, TransactionReport};
+
+mod snapshot;
+pub use snapshot::{RestoreReport, Snapshot, SnapshotError};
+
+pub mod transport;
+pub use transport::Transport;
+
+mod watch;
+pub use watch::{Captures, Pattern, PatternError, WatchUpdate};
+
+#[cfg(feature = "mock")]
+pub mod mock;
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
 mod discover;
 pub use discover::*;
@@ -0,0 +1,298 @@
+//! In-process stand-in for the HTTP datastore of a MOTU interface.
+//!
+//! The server speaks the same protocol `Device` uses: `GET /apiversion`, `GET /datastore`
+//! with ETag / `If-None-Match` long polling, and `PATCH /datastore` with a multipart `json`
+//! field. It can be seeded from a datastore dump and scripted to push external changes,
+//! which makes it possible to test against a "device" without one on the bench.
+//!
+//! The datastore behind the server is a [`MemoryTransport`], use that directly when the
+//! HTTP layer isn't what's under test.
+
+use crate::device::{Device, DeviceType};
+use crate::transport::{LongPoll, MemoryTransport, Store};
+use hyper::header::{CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{Map, Value as SerdeValue};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::watch;
+
+pub use crate::transport::Patch;
+
+/// A running mock datastore, the server shuts down when this is dropped
+#[derive(Debug)]
+pub struct MockServer {
+    addr: SocketAddr,
+    store: MemoryTransport,
+    shutdown: watch::Sender<bool>,
+}
+
+impl MockServer {
+    /// Starts a mock with an empty datastore
+    pub async fn start() -> Result<MockServer, MockError> {
+        Self::with_values(HashMap::new()).await
+    }
+
+    /// Starts a mock seeded from a flat datastore dump, as returned by `GET /datastore`
+    pub async fn from_json(json_data: &str) -> Result<MockServer, MockError> {
+        Self::with_values(serde_json::from_str(json_data)?).await
+    }
+
+    pub async fn with_values(values: HashMap<String, SerdeValue>) -> Result<MockServer, MockError> {
+        Self::with_options(values, Duration::from_secs(15)).await
+    }
+
+    /// Starts a mock where pending long polls answer `304 Not Modified` after `long_poll_timeout`
+    pub async fn with_options(
+        values: HashMap<String, SerdeValue>,
+        long_poll_timeout: Duration,
+    ) -> Result<MockServer, MockError> {
+        Self::serve(MemoryTransport::with_options(values, long_poll_timeout)).await
+    }
+
+    /// Serves an existing in-memory datastore over HTTP
+    pub async fn serve(store: MemoryTransport) -> Result<MockServer, MockError> {
+        let shutdown = watch::channel(false).0;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+
+        let svc_store = store.clone();
+        let svc_shutdown = shutdown.clone();
+        let make_svc = make_service_fn(move |_| {
+            let store = svc_store.clone();
+            let shutdown = svc_shutdown.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    handle(store.clone(), shutdown.subscribe(), req)
+                }))
+            }
+        });
+
+        let mut rx = shutdown.subscribe();
+        let server = Server::from_tcp(listener)?
+            .serve(make_svc)
+            .with_graceful_shutdown(async move {
+                let _ = rx.changed().await;
+            });
+
+        tokio::spawn(server);
+
+        Ok(MockServer {
+            addr,
+            store,
+            shutdown,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The datastore behind the server
+    pub fn store(&self) -> &MemoryTransport {
+        &self.store
+    }
+
+    /// A new `Device` pointing at this mock
+    pub fn device(&self) -> Device {
+        Device::new(
+            "Mock",
+            &self.addr.ip().to_string(),
+            self.addr.port(),
+            "0001f2fffe000000",
+            DeviceType::Device,
+        )
+    }
+
+    /// Changes a key as if someone turned a knob on the front panel
+    pub fn push(&self, key: &str, value: impl Into<SerdeValue>) {
+        self.store.push(key, value);
+    }
+
+    /// Changes several keys at once, they are delivered in the same long poll response
+    pub fn push_many(&self, values: HashMap<String, SerdeValue>) {
+        self.store.push_many(values);
+    }
+
+    pub fn get(&self, key: &str) -> Option<SerdeValue> {
+        self.store.get(key)
+    }
+
+    /// Full contents of the datastore
+    pub fn values(&self) -> HashMap<String, SerdeValue> {
+        self.store.values()
+    }
+
+    /// Every `PATCH` received so far, oldest first
+    pub fn patches(&self) -> Vec<Patch> {
+        self.store.patches()
+    }
+
+    pub fn set_api_version(&self, version: &str) {
+        self.store.set_api_version(version);
+    }
+
+    /// Simulates the device dropping off the network, every request fails with
+    /// `503 Service Unavailable` until the mock is brought back online
+    pub fn set_offline(&self, offline: bool) {
+        self.store.set_offline(offline);
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.shutdown.send_replace(true);
+    }
+}
+
+async fn handle(
+    store: MemoryTransport,
+    mut shutdown: watch::Receiver<bool>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if store.store().offline {
+        return Ok(status(StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    let path = req.uri().path().trim_matches('/').to_string();
+    let client = client_id(req.uri().query());
+
+    let res = match (req.method(), path.as_str()) {
+        (&Method::GET, "apiversion") => {
+            Response::new(Body::from(store.store().api_version.clone()))
+        }
+        (&Method::GET, "datastore") => {
+            let since = req
+                .headers()
+                .get(IF_NONE_MATCH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim_matches('"').parse::<u64>().ok());
+
+            match since {
+                Some(since) => tokio::select! {
+                    res = store.long_poll(since, client) => match res {
+                        LongPoll::Changed(version, m) => json_response(&m, Some(version)),
+                        LongPoll::NotModified => status(StatusCode::NOT_MODIFIED),
+                        LongPoll::Offline => status(StatusCode::SERVICE_UNAVAILABLE),
+                    },
+                    _ = shutdown.changed() => status(StatusCode::SERVICE_UNAVAILABLE),
+                },
+                None => {
+                    let s = store.store();
+                    json_response(&s.changes_since(0, None), Some(s.version))
+                }
+            }
+        }
+        (&Method::GET, p) if p.starts_with("datastore/") => {
+            subtree(&store.store(), &p["datastore/".len()..])
+        }
+        (&Method::PATCH, "datastore") => patch(&store, req, client).await,
+        _ => status(StatusCode::NOT_FOUND),
+    };
+
+    Ok(res)
+}
+
+async fn patch(store: &MemoryTransport, req: Request<Body>, client: Option<u32>) -> Response<Body> {
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(v) => v,
+        Err(_) => return status(StatusCode::BAD_REQUEST),
+    };
+
+    let values: HashMap<String, SerdeValue> = match multipart_field(&content_type, &body, "json")
+        .and_then(|v| serde_json::from_str(&v).ok())
+    {
+        Some(v) => v,
+        None => return status(StatusCode::BAD_REQUEST),
+    };
+
+    store.apply_patch(values, client);
+
+    status(StatusCode::NO_CONTENT)
+}
+
+/// `GET /datastore/<path>` returns `{"value": ..}` for a single key and the relative keys
+/// of everything below `path` otherwise
+fn subtree(store: &Store, path: &str) -> Response<Body> {
+    if let Some(e) = store.entries.get(path) {
+        let mut m = Map::new();
+        m.insert("value".to_string(), e.value.clone());
+        return json_response(&m, None);
+    }
+
+    let prefix = format!("{}/", path);
+    let m: Map<String, SerdeValue> = store
+        .entries
+        .iter()
+        .filter_map(|(k, e)| Some((k.strip_prefix(&prefix)?.to_string(), e.value.clone())))
+        .collect();
+
+    match m.is_empty() {
+        true => status(StatusCode::NOT_FOUND),
+        false => json_response(&m, None),
+    }
+}
+
+fn json_response(m: &Map<String, SerdeValue>, etag: Option<u64>) -> Response<Body> {
+    let mut res = Response::builder().header(CONTENT_TYPE, "application/json");
+    if let Some(v) = etag {
+        res = res.header(ETAG, v.to_string());
+    }
+
+    res.body(Body::from(SerdeValue::Object(m.clone()).to_string()))
+        .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = code;
+    res
+}
+
+fn client_id(query: Option<&str>) -> Option<u32> {
+    query?
+        .split('&')
+        .filter_map(|p| p.split_once('='))
+        .find(|(k, _)| *k == "client")
+        .and_then(|(_, v)| v.parse().ok())
+}
+
+/// Minimal multipart/form-data parser, just enough to pull out the text field `set_keys` sends
+fn multipart_field(content_type: &str, body: &[u8], field: &str) -> Option<String> {
+    let boundary = content_type
+        .split(';')
+        .map(|v| v.trim())
+        .find_map(|v| v.strip_prefix("boundary="))?
+        .trim_matches('"');
+
+    let body = std::str::from_utf8(body).ok()?;
+    let name = format!("name=\"{}\"", field);
+
+    body.split(&format!("--{}", boundary))
+        .filter_map(|part| part.split_once("\r\n\r\n"))
+        .find(|(headers, _)| headers.contains(&name))
+        .map(|(_, content)| content.trim_end_matches("\r\n").to_string())
+}
+
+#[derive(Error, Debug)]
+pub enum MockError {
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error(transparent)]
+    ServerError(#[from] hyper::Error),
+    #[error(transparent)]
+    SerializationError(#[from] serde_json::Error),
+}
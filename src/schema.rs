@@ -0,0 +1,478 @@
+//! Registry of known datastore keys.
+//!
+//! Every entry describes a family of keys by a [`Pattern`] together with the type of value
+//! the device stores there, its unit, the values it accepts and whether it can be written.
+//! `Value::decode` uses it to decide how strings are interpreted and `Device::set_keys`
+//! validates writes against it before anything is sent to the device.
+
+use crate::value::Value;
+use crate::watch::Pattern;
+use std::fmt::Display;
+use thiserror::Error;
+
+lazy_static! {
+    static ref MOTU: Schema = Schema::motu_builtin();
+}
+
+/// How a value is stored in the datastore, named after the types in the MOTU docs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValueType {
+    String,
+    Int,
+    Real,
+    /// `0` or `1`
+    IntBool,
+    /// Two colon separated ints, like ranges or routing sources
+    IntPair,
+    /// Colon separated ints
+    IntList,
+}
+
+impl Display for ValueType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let t = match self {
+            ValueType::String => "string",
+            ValueType::Int => "int",
+            ValueType::Real => "real",
+            ValueType::IntBool => "int_bool",
+            ValueType::IntPair => "int_pair",
+            ValueType::IntList => "int_list",
+        };
+        write!(f, "{}", t)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Unit {
+    Decibel,
+    Hertz,
+    /// Linear gain, 1.0 is unity
+    Gain,
+}
+
+impl Display for Unit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let u = match self {
+            Unit::Decibel => "dB",
+            Unit::Hertz => "Hz",
+            Unit::Gain => "gain",
+        };
+        write!(f, "{}", u)
+    }
+}
+
+/// Values a key accepts on top of its type
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constraint {
+    /// Inclusive numeric range
+    Range(f64, f64),
+    OneOf(Vec<i64>),
+}
+
+impl Display for Constraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Constraint::Range(min, max) => write!(f, "{}..={}", min, max),
+            Constraint::OneOf(v) => {
+                let v: Vec<String> = v.iter().map(|v| v.to_string()).collect();
+                write!(f, "one of {}", v.join(", "))
+            }
+        }
+    }
+}
+
+/// Description of a family of datastore keys
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeySchema {
+    pub path: Pattern,
+    pub value_type: ValueType,
+    pub unit: Option<Unit>,
+    pub constraint: Option<Constraint>,
+    pub read_only: bool,
+    pub description: String,
+}
+
+impl KeySchema {
+    /// A writable key without unit or constraint
+    pub fn new(path: Pattern, value_type: ValueType, description: &str) -> KeySchema {
+        KeySchema {
+            path,
+            value_type,
+            unit: None,
+            constraint: None,
+            read_only: false,
+            description: description.to_string(),
+        }
+    }
+
+    pub fn unit(mut self, unit: Unit) -> KeySchema {
+        self.unit = Some(unit);
+        self
+    }
+
+    pub fn range(mut self, min: f64, max: f64) -> KeySchema {
+        self.constraint = Some(Constraint::Range(min, max));
+        self
+    }
+
+    pub fn one_of(mut self, values: &[i64]) -> KeySchema {
+        self.constraint = Some(Constraint::OneOf(values.to_vec()));
+        self
+    }
+
+    pub fn read_only(mut self) -> KeySchema {
+        self.read_only = true;
+        self
+    }
+
+    /// Checks that `value` could be written to `key`, which is assumed to match `path`
+    pub fn validate(&self, key: &str, value: &Value) -> Result<(), SchemaError> {
+        if self.read_only {
+            return Err(SchemaError::ReadOnly(key.to_string()));
+        }
+
+        let wrong_type = || SchemaError::WrongType {
+            key: key.to_string(),
+            expected: self.value_type,
+            value: value.clone(),
+        };
+
+        let number = match (self.value_type, value) {
+            (ValueType::String, Value::String(_)) => None,
+            (ValueType::Int, Value::Int(v)) => Some(*v as f64),
+            (ValueType::Real, Value::Int(v)) => Some(*v as f64),
+            (ValueType::Real, Value::Float(v)) => Some(*v),
+            (ValueType::IntBool, Value::Int(v)) if *v == 0 || *v == 1 => Some(*v as f64),
+            (ValueType::IntPair, Value::Pair(v)) if v.len() == 2 => None,
+            // Routing sources are unrouted with an empty string
+            (ValueType::IntPair, Value::String(v)) if v.is_empty() => None,
+            (ValueType::IntList, Value::Pair(_)) => None,
+            _ => return Err(wrong_type()),
+        };
+
+        if let Value::Pair(v) = value {
+            if v.iter().any(|v| v.parse::<i64>().is_err()) {
+                return Err(wrong_type());
+            }
+        }
+
+        if let (Some(c), Some(v)) = (&self.constraint, number) {
+            let ok = match c {
+                Constraint::Range(min, max) => v >= *min && v <= *max,
+                Constraint::OneOf(allowed) => allowed.iter().any(|a| *a as f64 == v),
+            };
+
+            if !ok {
+                return Err(SchemaError::NotAllowed {
+                    key: key.to_string(),
+                    value: value.clone(),
+                    constraint: c.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for KeySchema {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}", self.path, self.value_type)?;
+        if let Some(u) = &self.unit {
+            write!(f, ", {}", u)?;
+        }
+        if let Some(c) = &self.constraint {
+            write!(f, ", {}", c)?;
+        }
+        if self.read_only {
+            write!(f, ", read only")?;
+        }
+        write!(f, "): {}", self.description)
+    }
+}
+
+/// A set of key descriptions, the first entry matching a key wins
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Schema {
+    keys: Vec<KeySchema>,
+}
+
+impl Schema {
+    pub fn new() -> Schema {
+        Self::default()
+    }
+
+    /// The keys documented for MOTU AVB interfaces
+    pub fn motu() -> &'static Schema {
+        &MOTU
+    }
+
+    pub fn register(&mut self, key: KeySchema) {
+        self.keys.push(key);
+    }
+
+    /// Looks up the description of a concrete key like `ext/ibank/0/ch/1/trim`
+    pub fn describe(&self, key: &str) -> Option<&KeySchema> {
+        self.keys.iter().find(|k| k.path.matches(key).is_some())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &KeySchema> {
+        self.keys.iter()
+    }
+
+    /// Checks that `value` can be written to `key`. Keys the schema doesn't know about
+    /// are let through, the device is the final judge of those.
+    pub fn validate(&self, key: &str, value: &Value) -> Result<(), SchemaError> {
+        match self.describe(key) {
+            Some(k) => k.validate(key, value),
+            None => Ok(()),
+        }
+    }
+
+    fn motu_builtin() -> Schema {
+        use ValueType as T;
+
+        let key = |path: &str, t: ValueType, description: &str| {
+            KeySchema::new(path.parse().unwrap(), t, description)
+        };
+
+        let mut s = Schema::new();
+        let keys = vec![
+            key("uid", T::String, "Unique ID of the device").read_only(),
+            key("ext/caps/avb", T::Int, "Version of the AVB feature set").read_only(),
+            key(
+                "ext/caps/router",
+                T::Int,
+                "Version of the router feature set",
+            )
+            .read_only(),
+            key("ext/caps/mixer", T::Int, "Version of the mixer feature set").read_only(),
+            key("ext/clockLocked", T::IntBool, "True if the clock is locked").read_only(),
+            key("ext/wordClockMode", T::String, "Word clock output mode"),
+            key("ext/wordClockThru", T::String, "Word clock thru mode"),
+            key(
+                "ext/smuxPerBank",
+                T::IntBool,
+                "True if each optical bank has its own SMUX setting",
+            )
+            .read_only(),
+            key(
+                "ext/enableHostVolControls",
+                T::IntBool,
+                "Let the computer control the output volume",
+            ),
+            // Channel banks
+            key("ext/*/*/name", T::String, "Name of the bank"),
+            key(
+                "ext/*/*/numCh",
+                T::Int,
+                "Number of channels available at the current sample rate",
+            )
+            .read_only(),
+            key(
+                "ext/*/*/maxCh",
+                T::Int,
+                "Maximum number of channels in the bank",
+            )
+            .read_only(),
+            key(
+                "ext/*/*/userCh",
+                T::Int,
+                "Number of channels the user has enabled",
+            ),
+            key(
+                "ext/*/*/calcCh",
+                T::Int,
+                "Number of channels that are actually active",
+            )
+            .read_only(),
+            key(
+                "ext/*/*/smux",
+                T::String,
+                "SMUX mode of an optical bank, toslink or adat",
+            ),
+            key("ext/*/*/madiClock", T::String, "Clock mode of a MADI bank"),
+            key(
+                "ext/*/*/madiFormat",
+                T::Int,
+                "Number of channels of a MADI bank",
+            )
+            .one_of(&[56, 64]),
+            // Channels
+            key(
+                "ext/*/*/ch/*/name",
+                T::String,
+                "User set name of the channel",
+            ),
+            key(
+                "ext/*/*/ch/*/defaultName",
+                T::String,
+                "Name of the channel if the user didn't set one",
+            )
+            .read_only(),
+            key(
+                "ext/obank/*/ch/*/src",
+                T::IntPair,
+                "Routing source as bank:channel, empty if unrouted",
+            ),
+            key(
+                "ext/*/*/ch/*/trim",
+                T::Int,
+                "Trim of the channel, see trimRange",
+            )
+            .unit(Unit::Decibel),
+            key("ext/*/*/ch/*/trimRange", T::IntPair, "Range of trim")
+                .unit(Unit::Decibel)
+                .read_only(),
+            key(
+                "ext/*/*/ch/*/stereoTrim",
+                T::Int,
+                "Trim of a stereo pair, see stereoTrimRange",
+            )
+            .unit(Unit::Decibel),
+            key(
+                "ext/*/*/ch/*/stereoTrimRange",
+                T::IntPair,
+                "Range of stereoTrim",
+            )
+            .unit(Unit::Decibel)
+            .read_only(),
+            key("ext/*/*/ch/*/pad", T::IntBool, "True if the pad is engaged"),
+            key(
+                "ext/*/*/ch/*/phase",
+                T::IntBool,
+                "True if the phase is inverted",
+            ),
+            key(
+                "ext/*/*/ch/*/48V",
+                T::IntBool,
+                "True if phantom power is engaged",
+            ),
+            key(
+                "ext/*/*/ch/*/connection",
+                T::IntBool,
+                "True if something is plugged in",
+            )
+            .read_only(),
+            // AVB
+            key(
+                "avb/*/entity_name",
+                T::String,
+                "Name of the device on the AVB network",
+            ),
+            key("avb/*/model_name", T::String, "Model of the device").read_only(),
+            key("avb/*/vendor_name", T::String, "Maker of the device").read_only(),
+            key(
+                "avb/*/firmware_version",
+                T::String,
+                "Firmware version of the device",
+            )
+            .read_only(),
+            key(
+                "avb/*/serial_number",
+                T::String,
+                "Serial number of the device",
+            )
+            .read_only(),
+            key(
+                "avb/*/cfg/*/object_name",
+                T::String,
+                "Name of the configuration",
+            )
+            .read_only(),
+            key(
+                "avb/*/cfg/*/input_streams/*/name",
+                T::String,
+                "Name of an input stream",
+            ),
+            key(
+                "avb/*/cfg/*/output_streams/*/name",
+                T::String,
+                "Name of an output stream",
+            ),
+            key(
+                "avb/*/cfg/*/clock_sources/*/object_name",
+                T::String,
+                "Name of a clock source",
+            )
+            .read_only(),
+            key(
+                "avb/*/master_clock/capable",
+                T::IntBool,
+                "True if the device can be the clock master",
+            )
+            .read_only(),
+            key("avb/*/cfg/*/current_sampling_rate", T::Int, "Sample rate")
+                .unit(Unit::Hertz)
+                .one_of(&[44100, 48000, 88200, 96000, 176400, 192000]),
+            key(
+                "avb/*/cfg/*/sample_rates",
+                T::IntList,
+                "Supported sample rates",
+            )
+            .unit(Unit::Hertz)
+            .read_only(),
+            key(
+                "avb/*/cfg/*/clock_source_index",
+                T::Int,
+                "Index of the clock source",
+            ),
+            // Mixer
+            key(
+                "mix/chan/*/config/format",
+                T::IntPair,
+                "Channel format, mono or stereo",
+            ),
+            key("mix/*/*/matrix/fader", T::Real, "Fader level")
+                .unit(Unit::Gain)
+                .range(0.0, 4.0),
+            key("mix/*/*/matrix/mute", T::IntBool, "True if muted"),
+            key("mix/*/*/matrix/solo", T::IntBool, "True if soloed"),
+            key(
+                "mix/*/*/matrix/pan",
+                T::Real,
+                "Pan, -1 is left and 1 is right",
+            )
+            .range(-1.0, 1.0),
+            key(
+                "mix/chan/*/matrix/*/*/send",
+                T::Real,
+                "Send level to an aux, group or reverb bus",
+            )
+            .unit(Unit::Gain)
+            .range(0.0, 4.0),
+            key(
+                "mix/*/*/hpf/enable",
+                T::IntBool,
+                "True if the high pass filter is enabled",
+            ),
+            key("mix/*/*/hpf/freq", T::Real, "High pass filter cutoff")
+                .unit(Unit::Hertz)
+                .range(20.0, 20000.0),
+        ];
+
+        for k in keys {
+            s.register(k);
+        }
+
+        s
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SchemaError {
+    #[error("`{0}` is read only")]
+    ReadOnly(String),
+    #[error("`{key}` expects {expected}, got `{value}`")]
+    WrongType {
+        key: String,
+        expected: ValueType,
+        value: Value,
+    },
+    #[error("`{value}` is not allowed for `{key}`, expected {constraint}")]
+    NotAllowed {
+        key: String,
+        value: Value,
+        constraint: Constraint,
+    },
+}
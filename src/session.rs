@@ -0,0 +1,174 @@
+//! Recording and replaying of device sessions.
+//!
+//! A session is everything the long polling loop received plus every `PATCH` we sent, stored
+//! as one JSON object per line. Replaying a session into a `Device` reproduces the same
+//! `Update` stream and channel bank state without a network, which turns field bug reports
+//! into regression tests.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value as SerdeValue;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+/// A single recorded event
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionEvent {
+    /// Milliseconds since the recording started
+    pub at_ms: u64,
+    #[serde(flatten)]
+    pub kind: SessionEventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionEventKind {
+    /// Body of a `GET /datastore` response, the first one is the full initial cache pass
+    Poll {
+        etag: Option<String>,
+        body: HashMap<String, SerdeValue>,
+    },
+    /// Body of a `PATCH /datastore` the device accepted
+    Patch { body: HashMap<String, SerdeValue> },
+}
+
+impl SessionEvent {
+    pub fn at(&self) -> Duration {
+        Duration::from_millis(self.at_ms)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub events: Vec<SessionEvent>,
+}
+
+impl Session {
+    pub fn load(path: impl AsRef<Path>) -> Result<Session, SessionError> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads a session stored as JSON lines, blank lines are skipped
+    pub fn from_reader(r: impl BufRead) -> Result<Session, SessionError> {
+        let mut events = Vec::new();
+
+        for line in r.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            events.push(serde_json::from_str(&line)?);
+        }
+
+        Ok(Session { events })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SessionError> {
+        self.to_writer(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn to_writer(&self, mut w: impl Write) -> Result<(), SessionError> {
+        for e in self.events.iter() {
+            write_event(&mut w, e)?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
+
+fn write_event(w: &mut impl Write, e: &SessionEvent) -> Result<(), SessionError> {
+    serde_json::to_writer(&mut *w, e)?;
+    w.write_all(b"\n")?;
+    Ok(())
+}
+
+/// How fast a session is fed into a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayPace {
+    /// Feed every event right after the previous one
+    Immediate,
+    /// Wait between events like the original recording did
+    Recorded,
+}
+
+/// Appends session events to a writer as they happen, see `Device::start_recording`.
+/// The writing happens on a thread of its own so recording never blocks the polling task,
+/// dropping the recorder waits for everything recorded so far to be written.
+pub struct Recorder {
+    start: Instant,
+    tx: Option<mpsc::UnboundedSender<SessionEvent>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder")
+            .field("start", &self.start)
+            .finish()
+    }
+}
+
+impl Recorder {
+    /// Records into a new file at `path`, truncating it if it exists
+    pub fn create(path: impl AsRef<Path>) -> Result<Recorder, SessionError> {
+        Ok(Self::from_writer(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn from_writer(mut w: impl Write + Send + 'static) -> Recorder {
+        let (tx, mut rx) = mpsc::unbounded_channel::<SessionEvent>();
+
+        let writer = std::thread::spawn(move || {
+            while let Some(e) = rx.blocking_recv() {
+                // Flush every event so a crash doesn't take the interesting part of the
+                // session with it
+                let res = write_event(&mut w, &e).and_then(|_| Ok(w.flush()?));
+                if let Err(e) = res {
+                    tracing::warn!(error = %e, "could not write session event, recording stopped");
+                    return;
+                }
+            }
+        });
+
+        Recorder {
+            start: Instant::now(),
+            tx: Some(tx),
+            writer: Some(writer),
+        }
+    }
+
+    pub(crate) fn record(&self, kind: SessionEventKind) {
+        let e = SessionEvent {
+            at_ms: self.start.elapsed().as_millis() as u64,
+            kind,
+        };
+
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(e);
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        // Closing the channel lets the writer finish what is queued and exit
+        self.tx.take();
+        if let Some(w) = self.writer.take() {
+            let _ = w.join();
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error(transparent)]
+    SerializationError(#[from] serde_json::Error),
+    #[error("session has to start with a poll event")]
+    NoInitialPoll,
+}
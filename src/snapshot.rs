@@ -0,0 +1,236 @@
+//! Saving the whole datastore of a device and putting it back later.
+//!
+//! A [`Snapshot`] is the cache of a connected device plus enough metadata to tell where it
+//! came from. It serializes to JSON or TOML, both diff well enough to keep known good
+//! configurations in git. `Device::restore` only writes the keys that differ from what the
+//! device holds right now.
+
+use crate::device::{Device, DeviceError};
+use crate::schema::Schema;
+use crate::{KeyPath, Value};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value as SerdeValue;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Most keys sent in a single `PATCH` while restoring
+const RESTORE_BATCH: usize = 64;
+
+/// Every key of a device's datastore at one point in time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub uid: String,
+    /// The `avb/<uid>/model_name` of the device, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// What `/apiversion` answered
+    pub api_version: String,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    /// Values as the datastore encodes them. Serialized with plain values before objects
+    /// and arrays, at every level, because TOML can't have a key after a table.
+    #[serde(serialize_with = "tables_last")]
+    pub values: BTreeMap<String, SerdeValue>,
+}
+
+/// What `Device::restore` did with the keys of a snapshot
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RestoreReport {
+    /// Keys that differed from the device and were written
+    pub written: Vec<KeyPath>,
+    /// Keys the schema marks read only, these are never written
+    pub read_only: Vec<KeyPath>,
+    /// Keys the device doesn't have, like the channels of a different model
+    pub missing: Vec<KeyPath>,
+}
+
+impl Snapshot {
+    pub(crate) async fn take(device: &Device) -> Result<Snapshot, DeviceError> {
+        let cache = device.get();
+        if cache.is_empty() {
+            return Err(DeviceError::NotConnected);
+        }
+
+        let api_version = device.api_version().await?;
+        let (uid, model) = origin(device);
+
+        // TOML has no null, and there is nothing to write back for one anyway
+        let values = cache
+            .iter()
+            .map(|e| (e.key().to_string(), e.value().encode()))
+            .filter(|(_, v)| !v.is_null())
+            .collect();
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        Ok(Snapshot {
+            uid,
+            model,
+            api_version,
+            timestamp,
+            values,
+        })
+    }
+
+    /// Writes every key that passes `filter`, is writable and differs from the device.
+    /// Unless `any_device` is set the snapshot has to come from this very device.
+    pub(crate) async fn restore(
+        &self,
+        device: &Device,
+        any_device: bool,
+        mut filter: impl FnMut(&KeyPath) -> bool,
+    ) -> Result<RestoreReport, DeviceError> {
+        let cache = device.get();
+        if cache.is_empty() {
+            return Err(DeviceError::NotConnected);
+        }
+
+        let (uid, model) = origin(device);
+        let same_model = match (&self.model, &model) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        if !any_device && (self.uid != uid || !same_model) {
+            return Err(DeviceError::SnapshotMismatch(
+                describe(&self.uid, &self.model),
+                describe(&uid, &model),
+            ));
+        }
+
+        let mut report = RestoreReport::default();
+        let mut changes = Vec::new();
+
+        for (k, raw) in self.values.iter() {
+            let key: KeyPath = k.parse()?;
+            if !filter(&key) {
+                continue;
+            }
+
+            if Schema::motu().describe(k).is_some_and(|s| s.read_only) {
+                report.read_only.push(key);
+                continue;
+            }
+
+            // Everything is checked before the first batch goes out, a bad entry halfway
+            // through would otherwise leave the device half restored
+            let val = Value::try_from(raw.clone()).and_then(|v| v.decode(k))?;
+            match cache.get(k.as_str()) {
+                Some(current) if *current == val => {}
+                Some(_) => {
+                    Schema::motu().validate(k, &val)?;
+                    changes.push((key, val));
+                }
+                None => report.missing.push(key),
+            }
+        }
+
+        for batch in changes.chunks(RESTORE_BATCH) {
+            device.patch(batch.to_vec()).await?;
+            report.written.extend(batch.iter().map(|(k, _)| k.clone()));
+        }
+
+        Ok(report)
+    }
+
+    pub fn to_json(&self) -> Result<String, SnapshotError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(s: &str) -> Result<Snapshot, SnapshotError> {
+        Ok(serde_json::from_str(s)?)
+    }
+
+    pub fn to_toml(&self) -> Result<String, SnapshotError> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    pub fn from_toml(s: &str) -> Result<Snapshot, SnapshotError> {
+        Ok(toml::from_str(s)?)
+    }
+
+    /// Writes the snapshot as TOML if the path ends in `.toml`, as JSON otherwise
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        let s = match is_toml(path.as_ref()) {
+            true => self.to_toml()?,
+            false => self.to_json()?,
+        };
+        Ok(std::fs::write(path, s)?)
+    }
+
+    /// Reads a snapshot written by `save`, the format is picked the same way
+    pub fn load(path: impl AsRef<Path>) -> Result<Snapshot, SnapshotError> {
+        let s = std::fs::read_to_string(path.as_ref())?;
+        match is_toml(path.as_ref()) {
+            true => Self::from_toml(&s),
+            false => Self::from_json(&s),
+        }
+    }
+}
+
+/// The uid and model name a device reports about itself
+fn origin(device: &Device) -> (String, Option<String>) {
+    let uid = match device.get_value("uid") {
+        Some(Value::String(v)) => v,
+        _ => device.uid().to_string(),
+    };
+
+    let model = match device.get_value(&format!("avb/{}/model_name", uid)) {
+        Some(Value::String(v)) => Some(v),
+        _ => None,
+    };
+
+    (uid, model)
+}
+
+fn tables_last<S: Serializer>(
+    values: &BTreeMap<String, SerdeValue>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let values: BTreeMap<_, _> = values.iter().map(|(k, v)| (k, TablesLast(v))).collect();
+    toml::ser::tables_last(&values, serializer)
+}
+
+/// A JSON value whose objects put their nested objects and arrays last
+struct TablesLast<'a>(&'a SerdeValue);
+
+impl Serialize for TablesLast<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            SerdeValue::Object(m) => {
+                let m: BTreeMap<_, _> = m.iter().map(|(k, v)| (k, TablesLast(v))).collect();
+                toml::ser::tables_last(&m, serializer)
+            }
+            SerdeValue::Array(a) => serializer.collect_seq(a.iter().map(TablesLast)),
+            v => v.serialize(serializer),
+        }
+    }
+}
+
+fn describe(uid: &str, model: &Option<String>) -> String {
+    match model {
+        Some(m) => format!("{} ({})", uid, m),
+        None => uid.to_string(),
+    }
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("toml"))
+}
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    #[error(transparent)]
+    TomlSerializeError(#[from] toml::ser::Error),
+    #[error(transparent)]
+    TomlDeserializeError(#[from] toml::de::Error),
+}
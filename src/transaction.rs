@@ -0,0 +1,105 @@
+use crate::device::{Device, DeviceError};
+use crate::{KeyPath, Request, Value};
+use std::collections::BTreeMap;
+
+/// Collects writes from anywhere and sends them to the device in a single `PATCH`.
+///
+/// Writing the same key twice keeps the last value. Every key is checked against
+/// [`crate::schema::Schema::motu`] on `commit` and nothing is sent if one of them fails.
+///
+/// ```no_run
+/// # async fn scene(d: motu_avb_api::Device) -> Result<(), motu_avb_api::device::DeviceError> {
+/// let banks = d.input_banks()?;
+/// let bank = banks.get(&0).unwrap();
+///
+/// let report = d
+///     .transaction()
+///     .push(bank.set_name("Drums"))
+///     .extend(bank.set_phantom_power(0, true))
+///     .extend(bank.set_channel_trim(0, 20))
+///     .commit()
+///     .await?;
+/// println!("{} keys the device doesn't seem to have", report.unknown.len());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    device: Device,
+    values: BTreeMap<KeyPath, Value>,
+}
+
+/// Which keys of a committed transaction the cache knew about.
+///
+/// This is what the device had when it was last polled, not a confirmation of the write.
+/// Use `Device::set_confirmed` to find out what the device actually holds.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransactionReport {
+    /// Keys that are in the cache
+    pub known: Vec<KeyPath>,
+    /// Keys that are not in the cache. Most likely the device doesn't have them either,
+    /// and it drops those without an error.
+    pub unknown: Vec<KeyPath>,
+}
+
+impl Transaction {
+    pub(crate) fn new(device: Device) -> Self {
+        Transaction {
+            device,
+            values: BTreeMap::new(),
+        }
+    }
+
+    pub fn set(&mut self, key: KeyPath, val: Value) -> &mut Self {
+        self.values.insert(key, val);
+        self
+    }
+
+    pub fn push(&mut self, r: Request) -> &mut Self {
+        self.set(r.key, r.val)
+    }
+
+    /// Adds every request, also takes the `Option<Request>` the channel bank helpers return
+    pub fn extend(&mut self, requests: impl IntoIterator<Item = Request>) -> &mut Self {
+        for r in requests {
+            self.push(r);
+        }
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.values.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Validates and sends everything in one `PATCH`, an empty transaction sends nothing.
+    /// The transaction is emptied once the device took the write and left as is on errors.
+    ///
+    /// Whether a key is known comes from the cache, so before the first `connect` every key
+    /// is reported as unknown.
+    pub async fn commit(&mut self) -> Result<TransactionReport, DeviceError> {
+        if self.values.is_empty() {
+            return Ok(TransactionReport::default());
+        }
+
+        let cache = self.device.get();
+        let (known, unknown) = self
+            .values
+            .keys()
+            .cloned()
+            .partition(|k| cache.contains_key(k));
+
+        let values = self.values.clone().into_iter().collect();
+        self.device.patch(values).await?;
+        self.values.clear();
+
+        Ok(TransactionReport { known, unknown })
+    }
+}
@@ -0,0 +1,570 @@
+//! The wire between a `Device` and its datastore.
+//!
+//! `Device` only ever does three things with the hardware: ask for `/apiversion`, poll
+//! `/datastore` and `PATCH` it. [`Transport`] is that surface, [`HttpTransport`] talks to a
+//! real interface over HTTP and [`MemoryTransport`] keeps the datastore in process, which is
+//! enough to get a fully connected `Device` with banks and updates in a unit test.
+
+use crate::device::{Device, DeviceError, DeviceType};
+use futures::future::BoxFuture;
+use reqwest::StatusCode;
+use serde_json::{Map, Value as SerdeValue};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How long `HttpTransport` waits for anything but a long poll unless told otherwise
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `HttpTransport` waits for a long poll unless told otherwise. The device answers
+/// a long poll after about 15 seconds even if nothing changed, so this leaves some slack.
+pub const DEFAULT_LONG_POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Result of a `GET /datastore`
+#[derive(Debug, Clone, PartialEq)]
+pub enum PollResponse {
+    /// Nothing changed since the etag we sent
+    NotModified,
+    /// The changed keys, or the whole datastore if we sent no etag
+    Changed {
+        etag: Option<String>,
+        body: HashMap<String, SerdeValue>,
+    },
+}
+
+/// How a `Device` reaches the datastore.
+///
+/// Implementations are shared between the handles and background tasks of a device, so
+/// every method takes `&self`.
+pub trait Transport: Send + Sync + std::fmt::Debug {
+    /// Fetches `/apiversion`, doubles as the health check
+    fn api_version(&self) -> BoxFuture<'_, Result<String, DeviceError>>;
+
+    /// Fetches the datastore for `client_id`. Without an etag this returns everything,
+    /// with one it waits until something changed or the device gives up waiting.
+    fn poll<'a>(
+        &'a self,
+        client_id: u32,
+        etag: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<PollResponse, DeviceError>>;
+
+    /// Writes `values` to the datastore on behalf of `client_id`
+    fn patch(
+        &self,
+        client_id: u32,
+        values: HashMap<String, SerdeValue>,
+    ) -> BoxFuture<'_, Result<(), DeviceError>>;
+
+    /// Reads the current value of a single key, None if the datastore doesn't have it.
+    /// Unlike `poll` this also sees changes made by `client_id` itself.
+    ///
+    /// The default fetches the whole datastore, implementations should override it if they
+    /// can read one key.
+    fn read<'a>(
+        &'a self,
+        client_id: u32,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<SerdeValue>, DeviceError>> {
+        Box::pin(async move {
+            match self.poll(client_id, None).await? {
+                PollResponse::Changed { mut body, .. } => Ok(body.remove(key)),
+                PollResponse::NotModified => Ok(None),
+            }
+        })
+    }
+}
+
+/// The default transport, talks to the device over HTTP
+#[derive(Debug, Clone)]
+pub struct HttpTransport {
+    client: reqwest::Client,
+    root: String,
+    url: String,
+    health: String,
+    user_agent: Option<String>,
+    request_timeout: Duration,
+    long_poll_timeout: Duration,
+}
+
+impl HttpTransport {
+    pub fn new(hostname: &str, port: u16) -> HttpTransport {
+        Self::with_client(reqwest::Client::new(), hostname, port)
+    }
+
+    pub fn with_client(client: reqwest::Client, hostname: &str, port: u16) -> HttpTransport {
+        let root = format!("http://{}:{}", hostname, port);
+        HttpTransport {
+            client,
+            url: format!("{}/datastore", root),
+            health: format!("{}/apiversion", root),
+            root,
+            user_agent: None,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            long_poll_timeout: DEFAULT_LONG_POLL_TIMEOUT,
+        }
+    }
+
+    /// Serves `/datastore` and `/apiversion` below `path` instead of the root
+    pub fn base_path(mut self, path: &str) -> Self {
+        let base = match path.trim_matches('/') {
+            "" => self.root.clone(),
+            path => format!("{}/{}", self.root, path),
+        };
+        self.url = format!("{}/datastore", base);
+        self.health = format!("{}/apiversion", base);
+        self
+    }
+
+    /// Sent as the `User-Agent` of every request
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = Some(user_agent.to_string());
+        self
+    }
+
+    /// Gives up on requests other than long polls after `timeout`,
+    /// [`DEFAULT_REQUEST_TIMEOUT`] unless set
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Stops waiting for a long poll after `timeout`, [`DEFAULT_LONG_POLL_TIMEOUT`] unless
+    /// set. The poll counts as "nothing changed" if the device still answers on
+    /// `/apiversion`, otherwise it fails.
+    pub fn long_poll_timeout(mut self, timeout: Duration) -> Self {
+        self.long_poll_timeout = timeout;
+        self
+    }
+
+    fn request(&self, req: reqwest::RequestBuilder, timeout: Duration) -> reqwest::RequestBuilder {
+        let req = match &self.user_agent {
+            Some(v) => req.header(reqwest::header::USER_AGENT, v),
+            None => req,
+        };
+
+        req.timeout(timeout)
+    }
+}
+
+impl Transport for HttpTransport {
+    fn api_version(&self) -> BoxFuture<'_, Result<String, DeviceError>> {
+        Box::pin(async move {
+            match self
+                .request(self.client.get(&self.health), self.request_timeout)
+                .send()
+                .await?
+                .error_for_status()
+            {
+                Ok(res) => Ok(res.text().await?),
+                Err(_) => Err(DeviceError::CouldNotConnect(self.health.clone())),
+            }
+        })
+    }
+
+    fn poll<'a>(
+        &'a self,
+        client_id: u32,
+        etag: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<PollResponse, DeviceError>> {
+        Box::pin(async move {
+            let c = self.client.get(&self.url).query(&[("client", client_id)]);
+
+            // If we are long polling, send the etag we got last time
+            let res = match etag {
+                Some(v) => {
+                    let c = c.header(reqwest::header::IF_NONE_MATCH, v);
+                    match self.request(c, self.long_poll_timeout).send().await {
+                        Err(e) if e.is_timeout() => {
+                            // Either the device held on to the poll for longer than usual
+                            // or it went away mid poll, only the latter is an error
+                            self.api_version().await?;
+                            return Ok(PollResponse::NotModified);
+                        }
+                        res => res?,
+                    }
+                }
+                None => self.request(c, self.request_timeout).send().await?,
+            };
+
+            if res.status() == StatusCode::NOT_MODIFIED {
+                return Ok(PollResponse::NotModified);
+            }
+
+            let res = res.error_for_status()?;
+            let etag = res
+                .headers()
+                .get(reqwest::header::ETAG)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string());
+
+            Ok(PollResponse::Changed {
+                etag,
+                body: res.json().await?,
+            })
+        })
+    }
+
+    fn patch(
+        &self,
+        client_id: u32,
+        values: HashMap<String, SerdeValue>,
+    ) -> BoxFuture<'_, Result<(), DeviceError>> {
+        Box::pin(async move {
+            let form =
+                reqwest::multipart::Form::new().text("json", serde_json::to_string(&values)?);
+
+            let req = self
+                .client
+                .patch(&self.url)
+                .query(&[("client", client_id)])
+                .multipart(form);
+            let res = self.request(req, self.request_timeout).send().await?;
+
+            match res.status() {
+                StatusCode::OK | StatusCode::NO_CONTENT => Ok(()),
+                status => Err(DeviceError::BadResponse(status, res.text().await?)),
+            }
+        })
+    }
+
+    fn read<'a>(
+        &'a self,
+        client_id: u32,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<SerdeValue>, DeviceError>> {
+        Box::pin(async move {
+            let req = self
+                .client
+                .get(format!("{}/{}", self.url, key))
+                .query(&[("client", client_id)]);
+            let res = self.request(req, self.request_timeout).send().await?;
+
+            if res.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+
+            // A single key comes back as `{"value": ..}`
+            let mut m: HashMap<String, SerdeValue> = res.error_for_status()?.json().await?;
+            Ok(m.remove("value"))
+        })
+    }
+}
+
+/// A datastore that lives in memory.
+///
+/// Behaves like the device: long polls wait for changes and a client never gets its own
+/// `PATCH` echoed back. Clones share the same datastore, so a test can keep one to push
+/// "front panel" changes while a `Device` uses the other.
+#[derive(Debug, Clone)]
+pub struct MemoryTransport {
+    inner: Arc<MemoryInner>,
+}
+
+#[derive(Debug)]
+struct MemoryInner {
+    store: Mutex<Store>,
+    // Bumped every time the store changes, wakes up pending long polls
+    changed: watch::Sender<u64>,
+    long_poll_timeout: Duration,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Store {
+    pub(crate) version: u64,
+    pub(crate) entries: HashMap<String, Entry>,
+    patches: Vec<Patch>,
+    pub(crate) api_version: String,
+    pub(crate) offline: bool,
+    datastore_failing: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    pub(crate) value: SerdeValue,
+    version: u64,
+    // Client that made the change, the device doesn't echo changes back to their author
+    origin: Option<u32>,
+}
+
+/// A `PATCH` the datastore received
+#[derive(Debug, Clone, PartialEq)]
+pub struct Patch {
+    pub client: Option<u32>,
+    pub values: HashMap<String, SerdeValue>,
+}
+
+/// Outcome of waiting for changes in a `MemoryTransport`
+pub(crate) enum LongPoll {
+    Changed(u64, Map<String, SerdeValue>),
+    NotModified,
+    Offline,
+}
+
+impl Store {
+    fn apply(&mut self, values: HashMap<String, SerdeValue>, origin: Option<u32>) {
+        self.version += 1;
+        for (key, value) in values.into_iter() {
+            self.entries.insert(
+                key,
+                Entry {
+                    value,
+                    version: self.version,
+                    origin,
+                },
+            );
+        }
+    }
+
+    pub(crate) fn changes_since(
+        &self,
+        version: u64,
+        client: Option<u32>,
+    ) -> Map<String, SerdeValue> {
+        self.entries
+            .iter()
+            .filter(|(_, e)| e.version > version && (e.origin.is_none() || e.origin != client))
+            .map(|(k, e)| (k.clone(), e.value.clone()))
+            .collect()
+    }
+}
+
+impl Default for MemoryTransport {
+    fn default() -> Self {
+        Self::with_values(HashMap::new())
+    }
+}
+
+impl MemoryTransport {
+    /// An empty datastore
+    pub fn new() -> MemoryTransport {
+        Self::default()
+    }
+
+    /// A datastore seeded from a flat dump, as returned by `GET /datastore`
+    pub fn from_json(json_data: &str) -> Result<MemoryTransport, serde_json::Error> {
+        Ok(Self::with_values(serde_json::from_str(json_data)?))
+    }
+
+    pub fn with_values(values: HashMap<String, SerdeValue>) -> MemoryTransport {
+        Self::with_options(values, Duration::from_secs(15))
+    }
+
+    /// A datastore where pending long polls answer "not modified" after `long_poll_timeout`
+    pub fn with_options(
+        values: HashMap<String, SerdeValue>,
+        long_poll_timeout: Duration,
+    ) -> MemoryTransport {
+        let mut store = Store {
+            api_version: "0.0.0".to_string(),
+            ..Default::default()
+        };
+        store.apply(values, None);
+
+        MemoryTransport {
+            inner: Arc::new(MemoryInner {
+                changed: watch::channel(store.version).0,
+                store: Mutex::new(store),
+                long_poll_timeout,
+            }),
+        }
+    }
+
+    /// A new `Device` backed by this datastore
+    pub fn device(&self) -> Device {
+        Device::with_transport(
+            "Memory",
+            "localhost",
+            0,
+            "0001f2fffe000000",
+            DeviceType::Device,
+            self.clone(),
+        )
+    }
+
+    /// Changes a key as if someone turned a knob on the front panel
+    pub fn push(&self, key: &str, value: impl Into<SerdeValue>) {
+        self.push_many([(key.to_string(), value.into())].into_iter().collect());
+    }
+
+    /// Changes several keys at once, they are delivered in the same long poll response
+    pub fn push_many(&self, values: HashMap<String, SerdeValue>) {
+        self.apply(values, None);
+    }
+
+    pub fn get(&self, key: &str) -> Option<SerdeValue> {
+        self.store().entries.get(key).map(|e| e.value.clone())
+    }
+
+    /// Full contents of the datastore
+    pub fn values(&self) -> HashMap<String, SerdeValue> {
+        self.store()
+            .entries
+            .iter()
+            .map(|(k, e)| (k.clone(), e.value.clone()))
+            .collect()
+    }
+
+    /// Every `PATCH` received so far, oldest first
+    pub fn patches(&self) -> Vec<Patch> {
+        self.store().patches.clone()
+    }
+
+    pub fn set_api_version(&self, version: &str) {
+        self.store().api_version = version.to_string();
+    }
+
+    /// Simulates the device dropping off the network, every request fails until the
+    /// datastore is brought back online
+    pub fn set_offline(&self, offline: bool) {
+        let version = {
+            let mut store = self.store();
+            store.offline = offline;
+            store.version
+        };
+        // Wake up pending long polls so they notice
+        self.inner.changed.send_replace(version);
+    }
+
+    /// Simulates a device whose `/apiversion` still answers while every `/datastore`
+    /// request fails
+    pub fn set_datastore_failing(&self, failing: bool) {
+        let version = {
+            let mut store = self.store();
+            store.datastore_failing = failing;
+            store.version
+        };
+        self.inner.changed.send_replace(version);
+    }
+
+    pub(crate) fn store(&self) -> MutexGuard<'_, Store> {
+        self.inner.store.lock().unwrap()
+    }
+
+    /// Records a `PATCH` from `client` and applies it
+    pub(crate) fn apply_patch(&self, values: HashMap<String, SerdeValue>, client: Option<u32>) {
+        self.store().patches.push(Patch {
+            client,
+            values: values.clone(),
+        });
+        self.apply(values, client);
+    }
+
+    fn apply(&self, values: HashMap<String, SerdeValue>, origin: Option<u32>) {
+        let version = {
+            let mut store = self.store();
+            store.apply(values, origin);
+            store.version
+        };
+        self.inner.changed.send_replace(version);
+    }
+
+    /// Waits until something `client` hasn't seen changed after `since`, or the long poll
+    /// timeout runs out
+    pub(crate) async fn long_poll(&self, since: u64, client: Option<u32>) -> LongPoll {
+        let mut changed = self.inner.changed.subscribe();
+        let timeout = tokio::time::sleep(self.inner.long_poll_timeout);
+        tokio::pin!(timeout);
+
+        loop {
+            {
+                let store = self.store();
+                if store.offline || store.datastore_failing {
+                    return LongPoll::Offline;
+                }
+
+                let changes = store.changes_since(since, client);
+                if !changes.is_empty() {
+                    return LongPoll::Changed(store.version, changes);
+                }
+            }
+
+            tokio::select! {
+                _ = changed.changed() => {}
+                _ = &mut timeout => return LongPoll::NotModified,
+            }
+        }
+    }
+
+    fn check_online(&self) -> Result<(), DeviceError> {
+        match self.store().offline {
+            true => Err(DeviceError::CouldNotConnect(
+                "memory transport is offline".into(),
+            )),
+            false => Ok(()),
+        }
+    }
+
+    fn check_datastore(&self) -> Result<(), DeviceError> {
+        self.check_online()?;
+        match self.store().datastore_failing {
+            true => Err(DeviceError::BadResponse(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "memory transport datastore is failing".into(),
+            )),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn api_version(&self) -> BoxFuture<'_, Result<String, DeviceError>> {
+        Box::pin(async move {
+            self.check_online()?;
+            Ok(self.store().api_version.clone())
+        })
+    }
+
+    fn poll<'a>(
+        &'a self,
+        client_id: u32,
+        etag: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<PollResponse, DeviceError>> {
+        Box::pin(async move {
+            self.check_datastore()?;
+
+            let since = match etag.and_then(|v| v.trim_matches('"').parse::<u64>().ok()) {
+                Some(v) => v,
+                None => {
+                    let store = self.store();
+                    return Ok(PollResponse::Changed {
+                        etag: Some(store.version.to_string()),
+                        body: store.changes_since(0, None).into_iter().collect(),
+                    });
+                }
+            };
+
+            match self.long_poll(since, Some(client_id)).await {
+                LongPoll::Changed(version, m) => Ok(PollResponse::Changed {
+                    etag: Some(version.to_string()),
+                    body: m.into_iter().collect(),
+                }),
+                LongPoll::NotModified => Ok(PollResponse::NotModified),
+                LongPoll::Offline => self.check_datastore().map(|_| PollResponse::NotModified),
+            }
+        })
+    }
+
+    fn patch(
+        &self,
+        client_id: u32,
+        values: HashMap<String, SerdeValue>,
+    ) -> BoxFuture<'_, Result<(), DeviceError>> {
+        Box::pin(async move {
+            self.check_datastore()?;
+            self.apply_patch(values, Some(client_id));
+            Ok(())
+        })
+    }
+
+    fn read<'a>(
+        &'a self,
+        _client_id: u32,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<SerdeValue>, DeviceError>> {
+        Box::pin(async move {
+            self.check_datastore()?;
+            Ok(self.get(key))
+        })
+    }
+}
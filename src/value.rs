@@ -1,32 +1,84 @@
-use regex::Regex;
+use crate::schema::{Schema, ValueType};
 use serde::ser::{Serialize, Serializer};
 use serde_json::Value as SerdeValue;
 use thiserror::Error;
 
-lazy_static! {
-    static ref BOOL_MATCHER: Regex = Regex::new(r"bool:(.*)").unwrap();
-    static ref NAME_ESCAPE_MATCHER: Regex = Regex::new(r"(?i)(name)").unwrap();
-}
-
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct MEnum {
     pub value: i64,
     pub definitions: Vec<(i64, String)>,
 }
 
+impl MEnum {
+    /// Parses the part after `enum:`, e.g. `1:0=Off:1=On`.
+    /// Returns None unless encoding the result gives back exactly `s`.
+    fn parse(s: &str) -> Option<MEnum> {
+        let mut parts = s.split(':');
+        let value = parse_canonical(parts.next()?)?;
+
+        let definitions = parts
+            .map(|d| {
+                let (k, name) = d.split_once('=')?;
+                Some((parse_canonical(k)?, name.to_string()))
+            })
+            .collect::<Option<Vec<(i64, String)>>>()?;
+
+        Some(MEnum { value, definitions })
+    }
+
+    fn encode(&self) -> String {
+        let mut s = format!("enum:{}", self.value);
+        for (k, name) in self.definitions.iter() {
+            s.push_str(&format!(":{}={}", k, name));
+        }
+        s
+    }
+}
+
+/// Something the device sent that we can't decode, kept verbatim so it survives the cache
+/// and can be written back unchanged
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawValue {
+    pub raw: SerdeValue,
+    /// Best effort interpretation, used for casts and display
+    pub guess: Option<Box<Value>>,
+}
+
+impl PartialOrd for RawValue {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        self.raw.to_string().partial_cmp(&other.raw.to_string())
+    }
+}
+
+/// Parses an integer only if printing it again gives the same string, `01` or `+1` would
+/// not survive a round trip
+fn parse_canonical(s: &str) -> Option<i64> {
+    s.parse::<i64>().ok().filter(|v| v.to_string() == s)
+}
+
+/// A datastore value.
+///
+/// The datastore only holds JSON numbers, bools and strings, but MOTU packs enums and
+/// pairs into colon separated strings. `decode` unpacks those and `encode` packs them again,
+/// `Value::try_from(raw)?.decode(key)?.encode() == raw` holds for everything the device sends.
+/// Whatever isn't understood ends up as `Value::Raw` instead of failing.
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum Value {
     String(String),
     Float(f64),
     Int(i64),
     Bool(bool),
+    /// `enum:<value>:<n>=<name>:..`
     Enum(MEnum),
+    /// Colon separated numbers like ranges (`-127:0`) and routing sources (`0:1`)
     Pair(Vec<String>),
+    /// Anything we don't know how to decode
+    Raw(RawValue),
 }
 
 impl Value {
     pub fn decode(self, key: &str) -> Result<Value, ValueError> {
-        // Currently only have to deal with strings lol
+        // Only strings carry MOTU specific encodings
         // Why did MOTU have to reinvent JSON?
         // I hate this.
         let s = match &self {
@@ -34,68 +86,76 @@ impl Value {
             _ => return Ok(self),
         };
 
-        if s.len() == 0 {
+        if !s.contains(':') {
             return Ok(self);
         }
 
         // MOTU uses : to deliminate different variables but a name with : is valid so
-        // we need to escape : if the key contains :
+        // strings the schema knows to be plain strings are left alone
         // So dumb
-        if NAME_ESCAPE_MATCHER.is_match(key) {
+        if matches!(Schema::motu().describe(key), Some(k) if k.value_type == ValueType::String) {
             return Ok(self);
         }
 
-        // match weird bool
-        match BOOL_MATCHER.captures(key) {
-            Some(v) => {
-                let val = match &v[0] {
-                    "1" => true,
-                    "0" => false,
-                    _ => false,
-                };
+        let pair = Value::Pair(s.split(':').map(|f| f.to_string()).collect());
 
-                return Ok(Value::Bool(val));
-            }
-            None => {}
+        if let Some(v) = s.strip_prefix("enum:") {
+            return Ok(match MEnum::parse(v) {
+                Some(e) => Value::Enum(e),
+                None => Value::raw(self.encode(), Some(pair)),
+            });
         }
 
-        let spliced: Vec<&str> = s.split(":").collect();
-
-        // Check for matches
-        if spliced.len() == 0 {
-            return Ok(self);
+        if s.split(':').all(is_number) {
+            return Ok(pair);
         }
 
-        // Lets find out if this is an enum
+        Ok(Value::raw(self.encode(), Some(pair)))
+    }
 
-        if spliced[0] == "enum" {
-            let value = spliced[1].parse::<i64>()?;
-            let definitions: Vec<(i64, String)> = spliced
-                .iter()
-                .skip(2)
-                .map(|f| {
-                    let vm: Vec<&str> = f.split("=").collect();
-                    (vm[0].parse::<i64>().unwrap(), vm[1].to_string())
-                })
-                .collect();
+    fn raw(raw: SerdeValue, guess: Option<Value>) -> Value {
+        Value::Raw(RawValue {
+            raw,
+            guess: guess.map(Box::new),
+        })
+    }
 
-            return Ok(Value::Enum(MEnum { value, definitions }));
+    /// The decoded value, or the best guess for a `Value::Raw`
+    fn interpreted(&self) -> &Value {
+        match self {
+            Value::Raw(RawValue { guess: Some(v), .. }) => v,
+            _ => self,
         }
+    }
 
-        // TODO implement real
-        // TODO implement all the other weird shit MOTU stuffed into their key value store
-
-        // If not, it's a pair
-        Ok(Value::Pair(spliced.iter().map(|f| f.to_string()).collect()))
+    /// Encodes the value the way the datastore stores it, the inverse of `decode`
+    pub fn encode(&self) -> SerdeValue {
+        match self {
+            Value::String(v) => SerdeValue::String(v.clone()),
+            Value::Float(v) => serde_json::Number::from_f64(*v)
+                .map(SerdeValue::Number)
+                .unwrap_or(SerdeValue::Null),
+            Value::Int(v) => SerdeValue::from(*v),
+            Value::Bool(v) => SerdeValue::Bool(*v),
+            Value::Enum(v) => SerdeValue::String(v.encode()),
+            Value::Pair(v) => SerdeValue::String(v.join(":")),
+            Value::Raw(v) => v.raw.clone(),
+        }
     }
 }
 
+fn is_number(s: &str) -> bool {
+    s.bytes()
+        .all(|b| b.is_ascii_digit() || b == b'-' || b == b'.')
+        && s.parse::<f64>().is_ok()
+}
+
 impl TryFrom<SerdeValue> for Value {
     type Error = ValueError;
 
     fn try_from(val: SerdeValue) -> Result<Self, Self::Error> {
         match val {
-            SerdeValue::Null => Err(ValueError::NoValue),
+            SerdeValue::Null => Ok(Value::raw(val, None)),
             SerdeValue::Bool(v) => Ok(Value::Bool(v)),
             SerdeValue::Number(v) if v.is_f64() => Ok(Value::Float(
                 v.as_f64().ok_or(ValueError::UnableToParseFloat)?,
@@ -103,13 +163,16 @@ impl TryFrom<SerdeValue> for Value {
             SerdeValue::Number(v) if v.is_i64() => {
                 Ok(Value::Int(v.as_i64().ok_or(ValueError::UnableToParseInt)?))
             }
-            SerdeValue::Number(v) if v.is_u64() => Ok(Value::Int(
-                v.as_u64().ok_or(ValueError::UnableToParseInt)? as i64,
-            )),
-            SerdeValue::Number(_) => Err(ValueError::UnableToParseInt),
+            SerdeValue::Number(v) => match v.as_u64().map(i64::try_from) {
+                Some(Ok(i)) => Ok(Value::Int(i)),
+                // Too big for an Int, keep the exact number around
+                _ => {
+                    let guess = v.as_f64().map(Value::Float);
+                    Ok(Value::raw(SerdeValue::Number(v), guess))
+                }
+            },
             SerdeValue::String(v) => Ok(Value::String(v)),
-            SerdeValue::Array(_) => Err(ValueError::WTF),
-            SerdeValue::Object(_) => Err(ValueError::WTF),
+            SerdeValue::Array(_) | SerdeValue::Object(_) => Ok(Value::raw(val, None)),
         }
     }
 }
@@ -117,9 +180,9 @@ impl TryFrom<SerdeValue> for Value {
 impl TryFrom<&Value> for u32 {
     type Error = ValueError;
     fn try_from(val: &Value) -> Result<Self, Self::Error> {
-        match val {
+        match val.interpreted() {
             Value::String(v) => Ok(v.parse::<u32>()?),
-            Value::Int(v) => Ok(*v as u32),
+            Value::Int(v) => u32::try_from(*v).map_err(|_| ValueError::OutOfRange(*v)),
             _ => Err(ValueError::NoCasting),
         }
     }
@@ -128,9 +191,9 @@ impl TryFrom<&Value> for u32 {
 impl TryFrom<&Value> for i32 {
     type Error = ValueError;
     fn try_from(val: &Value) -> Result<Self, Self::Error> {
-        match val {
+        match val.interpreted() {
             Value::String(v) => Ok(v.parse::<i32>()?),
-            Value::Int(v) => Ok(*v as i32),
+            Value::Int(v) => i32::try_from(*v).map_err(|_| ValueError::OutOfRange(*v)),
             _ => Err(ValueError::NoCasting),
         }
     }
@@ -139,8 +202,11 @@ impl TryFrom<&Value> for i32 {
 impl TryFrom<&Value> for (i32, i32) {
     type Error = ValueError;
     fn try_from(val: &Value) -> Result<Self, Self::Error> {
-        match val {
-            Value::Pair(v) => Ok((v[0].parse::<i32>()?, v[1].parse::<i32>()?)),
+        match val.interpreted() {
+            Value::Pair(v) => match v.as_slice() {
+                [a, b] => Ok((a.parse::<i32>()?, b.parse::<i32>()?)),
+                _ => Err(ValueError::NotAPair(v.join(":"))),
+            },
             _ => Err(ValueError::NoCasting),
         }
     }
@@ -149,7 +215,7 @@ impl TryFrom<&Value> for (i32, i32) {
 impl TryFrom<&Value> for bool {
     type Error = ValueError;
     fn try_from(val: &Value) -> Result<Self, Self::Error> {
-        match val {
+        match val.interpreted() {
             Value::Bool(v) => Ok(*v),
             Value::Int(v) => match v {
                 0 => Ok(false),
@@ -176,14 +242,7 @@ impl std::fmt::Display for Value {
 
 impl From<Value> for String {
     fn from(val: Value) -> String {
-        match val {
-            Value::String(v) => v.clone(),
-            Value::Float(v) => v.to_string(),
-            Value::Int(v) => v.to_string(),
-            Value::Bool(v) => v.to_string(),
-            Value::Enum(v) => v.value.to_string(),
-            Value::Pair(v) => v.join(":"),
-        }
+        String::from(&val)
     }
 }
 
@@ -196,6 +255,11 @@ impl From<&Value> for String {
             Value::Bool(v) => v.to_string(),
             Value::Enum(v) => v.value.to_string(),
             Value::Pair(v) => v.join(":"),
+            Value::Raw(RawValue {
+                raw: SerdeValue::String(v),
+                ..
+            }) => v.clone(),
+            Value::Raw(v) => v.raw.to_string(),
         }
     }
 }
@@ -220,15 +284,10 @@ impl Serialize for Value {
             Value::String(v) => serializer.serialize_str(v),
             Value::Float(v) => serializer.serialize_f64(*v),
             Value::Int(v) => serializer.serialize_i64(*v),
-            Value::Bool(v) => {
-                let value = match v {
-                    true => "1",
-                    false => "0",
-                };
-                serializer.serialize_str(&format!("{}", value))
-            }
-            Value::Enum(v) => serializer.serialize_i64(v.value),
-            Value::Pair(_) => serializer.serialize_str(&String::from(self)),
+            Value::Bool(v) => serializer.serialize_bool(*v),
+            Value::Enum(v) => serializer.serialize_str(&v.encode()),
+            Value::Pair(v) => serializer.serialize_str(&v.join(":")),
+            Value::Raw(v) => v.raw.serialize(serializer),
         }
     }
 }
@@ -245,6 +304,10 @@ pub enum ValueError {
     WTF,
     #[error("could not cast")]
     NoCasting,
+    #[error("`{0}` is out of range")]
+    OutOfRange(i64),
+    #[error("expected two values, got `{0}`")]
+    NotAPair(String),
     #[error(transparent)]
     ParseIntError(#[from] std::num::ParseIntError),
 }
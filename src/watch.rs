@@ -0,0 +1,151 @@
+use crate::device::Update;
+use std::fmt::Display;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// A segment pattern for datastore keys.
+///
+/// Patterns are `/` separated like the keys themselves. `*` matches exactly one segment and
+/// `**` matches one or more segments at the end, so `ext/obank/*/ch/*/trim` matches the trim
+/// of every output channel and `mix/chan/3/**` matches every key under mixer channel 3, but
+/// not `mix/chan/3` itself.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Pattern {
+    segments: Vec<PatternSegment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum PatternSegment {
+    Literal(String),
+    Any,
+    Rest,
+}
+
+impl Pattern {
+    /// Matches a key against the pattern, returning the captured wildcard segments on success
+    pub fn matches(&self, key: &str) -> Option<Captures> {
+        let mut captures = Vec::new();
+        let mut parts = key.split('/');
+
+        for seg in self.segments.iter() {
+            match seg {
+                PatternSegment::Literal(l) => {
+                    if parts.next()? != l {
+                        return None;
+                    }
+                }
+                PatternSegment::Any => captures.push(parts.next()?.to_string()),
+                PatternSegment::Rest => {
+                    let rest = parts.collect::<Vec<&str>>().join("/");
+                    if rest.is_empty() {
+                        return None;
+                    }
+                    captures.push(rest);
+                    return Some(Captures(captures));
+                }
+            }
+        }
+
+        match parts.next() {
+            Some(_) => None,
+            None => Some(Captures(captures)),
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = PatternError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim_matches('/');
+        if s.is_empty() {
+            return Err(PatternError::Empty);
+        }
+
+        let parts: Vec<&str> = s.split('/').collect();
+        let mut segments = Vec::with_capacity(parts.len());
+
+        for (i, p) in parts.iter().enumerate() {
+            let seg = match *p {
+                "" => return Err(PatternError::EmptySegment(s.to_string())),
+                "**" if i != parts.len() - 1 => {
+                    return Err(PatternError::RestNotLast(s.to_string()))
+                }
+                "**" => PatternSegment::Rest,
+                "*" => PatternSegment::Any,
+                v => PatternSegment::Literal(v.to_string()),
+            };
+            segments.push(seg);
+        }
+
+        Ok(Pattern { segments })
+    }
+}
+
+impl TryFrom<&str> for Pattern {
+    type Error = PatternError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s: Vec<&str> = self
+            .segments
+            .iter()
+            .map(|s| match s {
+                PatternSegment::Literal(v) => v.as_str(),
+                PatternSegment::Any => "*",
+                PatternSegment::Rest => "**",
+            })
+            .collect();
+        write!(f, "{}", s.join("/"))
+    }
+}
+
+/// The key segments matched by the wildcards of a `Pattern`, in order.
+/// A trailing `**` captures the rest of the key as one `/` joined string.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Captures(Vec<String>);
+
+impl Captures {
+    pub fn get(&self, i: usize) -> Option<&str> {
+        self.0.get(i).map(|v| v.as_str())
+    }
+
+    /// Returns capture `i` parsed as a bank, channel or mixer index
+    pub fn index(&self, i: usize) -> Option<u32> {
+        self.get(i)?.parse().ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|v| v.as_str())
+    }
+}
+
+/// An update that matched a watched pattern
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchUpdate {
+    pub update: Update,
+    pub captures: Captures,
+}
+
+#[derive(Error, Debug)]
+pub enum PatternError {
+    #[error("pattern is empty")]
+    Empty,
+    #[error("pattern `{0}` contains an empty segment")]
+    EmptySegment(String),
+    #[error("`**` can only be used as the last segment of pattern `{0}`")]
+    RestNotLast(String),
+}
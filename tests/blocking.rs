@@ -0,0 +1,63 @@
+mod common;
+
+use common::TRIM;
+use motu_avb_api::blocking::Device;
+use motu_avb_api::transport::MemoryTransport;
+use motu_avb_api::{Request, Update, Value};
+use std::time::Duration;
+
+fn device() -> (MemoryTransport, Device) {
+    let t = common::transport();
+    let d = Device::from_async(t.device()).unwrap();
+    (t, d)
+}
+
+#[test]
+fn connects_and_writes_without_a_runtime() -> anyhow::Result<()> {
+    let (t, d) = device();
+    d.connect()?;
+
+    assert!(d.connection_state().is_connected());
+    assert_eq!(d.input_banks()?.len(), 5);
+
+    d.set(Request {
+        key: TRIM.parse()?,
+        val: Value::Int(-12),
+    })?;
+    d.set_keys(&[("ext/ibank/0/ch/0/trim", Value::Int(6))])?;
+
+    assert_eq!(d.get_value(TRIM), Some(Value::Int(-12)));
+    assert_eq!(t.patches().len(), 2);
+
+    let v = d.set_confirmed(
+        Request {
+            key: TRIM.parse()?,
+            val: Value::Int(-6),
+        },
+        Duration::from_secs(1),
+    )?;
+    assert_eq!(v, Value::Int(-6));
+
+    Ok(())
+}
+
+#[test]
+fn updates_iterate_until_disconnect() -> anyhow::Result<()> {
+    let (t, d) = device();
+    d.connect()?;
+
+    let mut updates = d.updates()?;
+    assert!(updates.try_next().is_none());
+
+    t.push("ext/ibank/0/ch/0/name", "Kick");
+    let update = updates
+        .find(|u| matches!(u, Update::External(k, _) if k == "ext/ibank/0/ch/0/name"))
+        .unwrap();
+    assert_eq!(update.value(), Some(&Value::String("Kick".into())));
+
+    // Whatever is still queued drains, then the iterator ends
+    d.disconnect()?;
+    assert!(updates.all(|u| !matches!(u, Update::External(..))));
+
+    Ok(())
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use common::{transport, trim, TRIM};
+use motu_avb_api::device::DeviceError;
+use motu_avb_api::{Request, Update, Value};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+#[tokio::test]
+async fn bursts_are_collapsed() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+
+    let mut updates = d.updates()?;
+    let c = d.coalescer(Duration::from_millis(100));
+
+    for v in 0..50 {
+        c.set(trim(-v))?;
+    }
+
+    // The cache and update stream don't wait for the device
+    assert_eq!(d.get_value(TRIM), Some(Value::Int(-49)));
+    for v in 0..50 {
+        assert_eq!(
+            updates.try_recv()?,
+            Update::Internal(TRIM.parse()?, Value::Int(-v))
+        );
+    }
+
+    c.flush().await?;
+    assert_eq!(t.patches().len(), 1);
+    assert_eq!(t.get(TRIM), Some(json!(-49)));
+    assert_eq!(c.pending(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn writes_are_rate_limited() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+
+    let c = d.coalescer(Duration::from_millis(100));
+
+    let start = Instant::now();
+    c.set(trim(-1))?;
+    c.flush().await?;
+    c.set(trim(-2))?;
+    c.flush().await?;
+
+    assert!(start.elapsed() >= Duration::from_millis(90));
+    assert_eq!(t.patches().len(), 2);
+    assert_eq!(t.get(TRIM), Some(json!(-2)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_writes_are_reported_on_flush() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+
+    let c = d.coalescer(Duration::from_millis(10));
+
+    // Schema violations don't make it into the queue
+    assert!(c
+        .set(Request {
+            key: "ext/obank/0/ch/0/connection".parse()?,
+            val: Value::Int(1),
+        })
+        .is_err());
+    assert_eq!(c.pending(), 0);
+
+    t.set_offline(true);
+    c.set(trim(-3))?;
+    assert!(matches!(c.flush().await, Err(DeviceError::RolledBack(_))));
+
+    // The error is only reported once
+    assert!(c.flush().await.is_ok());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_writes_roll_back() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+
+    let before = d.get_value(TRIM);
+    let mut updates = d.updates()?;
+    let c = d.coalescer(Duration::from_millis(10));
+
+    t.set_offline(true);
+    c.set(trim(-3))?;
+    assert_eq!(d.get_value(TRIM), Some(Value::Int(-3)));
+    assert!(c.flush().await.is_err());
+
+    assert_eq!(d.get_value(TRIM), before);
+    assert_eq!(
+        updates.recv().await?,
+        Update::Internal(TRIM.parse()?, Value::Int(-3))
+    );
+    match updates.recv().await? {
+        Update::Rollback(r) => {
+            assert_eq!(r.attempted, Value::Int(-3));
+            assert_eq!(r.restored, before);
+        }
+        u => panic!("expected a rollback, got {:?}", u),
+    }
+
+    Ok(())
+}
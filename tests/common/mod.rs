@@ -0,0 +1,139 @@
+//! Helpers shared by the integration tests, each test crate uses a different subset
+#![allow(dead_code)]
+
+use futures::future::BoxFuture;
+use motu_avb_api::device::{DeviceError, DeviceType};
+use motu_avb_api::transport::{MemoryTransport, PollResponse, Transport};
+use motu_avb_api::{ConnectionState, Device, Request, Update, Value};
+use serde_json::Value as SerdeValue;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::timeout;
+
+pub const WAIT: Duration = Duration::from_secs(5);
+
+pub const TRIM: &str = "ext/obank/0/ch/0/trim";
+
+/// The synthetic 624 from the fixtures
+pub fn transport() -> MemoryTransport {
+    MemoryTransport::from_json(include_str!("../fixtures/624.json")).unwrap()
+}
+
+pub fn trim(v: i64) -> Request {
+    Request {
+        key: TRIM.parse().unwrap(),
+        val: Value::Int(v),
+    }
+}
+
+/// The next value the device sent for `key`, skipping everything else
+pub async fn next_external(
+    updates: &mut tokio::sync::broadcast::Receiver<Update>,
+    key: &str,
+) -> Value {
+    timeout(WAIT, async {
+        loop {
+            if let Update::External(k, v) = updates.recv().await.unwrap() {
+                if k == key {
+                    return v;
+                }
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for update")
+}
+
+/// Waits for a connection state matching `f`, starting with the current one
+pub async fn wait_for_state(d: &Device, f: impl Fn(&ConnectionState) -> bool) {
+    use futures::StreamExt;
+
+    let mut events = d.connection_events();
+    timeout(WAIT, async {
+        while let Some(s) = events.next().await {
+            if f(&s) {
+                return;
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for connection state");
+}
+
+/// Checks `f` every 10ms until it holds, for things like the cache or banks that have no
+/// event of their own to wait for
+pub async fn wait_until(mut f: impl FnMut() -> bool) {
+    timeout(WAIT, async {
+        while !f() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("timed out waiting for condition");
+}
+
+type PatchHook = fn(HashMap<String, SerdeValue>) -> Option<HashMap<String, SerdeValue>>;
+
+/// A datastore that runs every `PATCH` through `hook` first, a hook returning `None`
+/// leaves the write unanswered. Leaves `read` to the default implementation.
+#[derive(Debug)]
+struct Hooked {
+    inner: MemoryTransport,
+    hook: PatchHook,
+}
+
+impl Transport for Hooked {
+    fn api_version(&self) -> BoxFuture<'_, Result<String, DeviceError>> {
+        self.inner.api_version()
+    }
+
+    fn poll<'a>(
+        &'a self,
+        client_id: u32,
+        etag: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<PollResponse, DeviceError>> {
+        self.inner.poll(client_id, etag)
+    }
+
+    fn patch(
+        &self,
+        client_id: u32,
+        values: HashMap<String, SerdeValue>,
+    ) -> BoxFuture<'_, Result<(), DeviceError>> {
+        match (self.hook)(values) {
+            Some(values) => self.inner.patch(client_id, values),
+            None => Box::pin(std::future::pending()),
+        }
+    }
+}
+
+fn hooked(name: &str, t: &MemoryTransport, hook: PatchHook) -> Device {
+    Device::with_transport(
+        name,
+        "localhost",
+        0,
+        "0001f2fffe000000",
+        DeviceType::Device,
+        Hooked {
+            inner: t.clone(),
+            hook,
+        },
+    )
+}
+
+/// A device that clamps trims to -10..=0 like the hardware clamps out of range values
+pub fn clamping(t: &MemoryTransport) -> Device {
+    hooked("Clamping", t, |mut values| {
+        for (k, v) in values.iter_mut() {
+            if let (true, Some(i)) = (k.ends_with("/trim"), v.as_i64()) {
+                *v = i.clamp(-10, 0).into();
+            }
+        }
+        Some(values)
+    })
+}
+
+/// A device that never answers a `PATCH`
+pub fn unresponsive(t: &MemoryTransport) -> Device {
+    hooked("Unresponsive", t, |_| None)
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use common::{clamping, transport, trim, TRIM, WAIT};
+use motu_avb_api::{Update, Value};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+#[tokio::test]
+async fn set_confirmed_returns_the_written_value() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+
+    let v = d.set_confirmed(trim(-20), Duration::from_secs(1)).await?;
+    assert_eq!(v, Value::Int(-20));
+    assert_eq!(t.get(TRIM), Some(json!(-20)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn set_confirmed_returns_clamped_values() -> anyhow::Result<()> {
+    let t = transport();
+    let d = clamping(&t);
+    d.connect().await?;
+    let mut updates = d.updates()?;
+
+    let v = d.set_confirmed(trim(-30), Duration::from_secs(1)).await?;
+    assert_eq!(v, Value::Int(-10));
+    assert_eq!(d.get_value(TRIM), Some(Value::Int(-10)));
+
+    assert_eq!(
+        updates.recv().await?,
+        Update::Internal(TRIM.parse()?, Value::Int(-30))
+    );
+    assert_eq!(
+        updates.recv().await?,
+        Update::External(TRIM.parse()?, Value::Int(-10))
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn set_confirmed_returns_right_away_when_clamped_to_the_prior_value() -> anyhow::Result<()> {
+    let t = transport();
+    let d = clamping(&t);
+    d.connect().await?;
+
+    // The trim is already at 0, clamping leaves it there and it never differs from before
+    d.set(trim(0)).await?;
+    let start = Instant::now();
+    let v = d.set_confirmed(trim(20), WAIT).await?;
+    assert_eq!(v, Value::Int(0));
+    assert_eq!(d.get_value(TRIM), Some(Value::Int(0)));
+    assert!(start.elapsed() < WAIT / 2);
+
+    Ok(())
+}
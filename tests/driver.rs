@@ -0,0 +1,124 @@
+mod common;
+
+use common::{transport, wait_until, WAIT};
+use motu_avb_api::device::DeviceError;
+use motu_avb_api::{Backoff, ConnectionState, Update, Value};
+use std::time::Duration;
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn driven_connection_runs_on_a_local_set() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+
+    let driver = d.connect_driven().await?;
+    assert!(d.connection_state().is_connected());
+    assert_eq!(d.input_banks()?.len(), 5);
+
+    let mut updates = d.updates()?;
+    let local = tokio::task::LocalSet::new();
+    let run = local.spawn_local(driver.run());
+
+    local
+        .run_until(async {
+            t.push("ext/ibank/0/ch/0/name", "Kick");
+            let upd = timeout(WAIT, updates.recv()).await??;
+            assert_eq!(
+                upd,
+                Update::External(
+                    "ext/ibank/0/ch/0/name".parse()?,
+                    Value::String("Kick".into())
+                )
+            );
+
+            // The mapping loop runs alongside the polling loop
+            wait_until(|| {
+                d.input_banks().unwrap().get(&0).unwrap().channels[&0]
+                    .name
+                    .as_deref()
+                    == Some("Kick")
+            })
+            .await;
+
+            d.disconnect().await?;
+            timeout(WAIT, run).await???;
+
+            anyhow::Ok(())
+        })
+        .await?;
+
+    assert!(!d.connection_state().is_connected());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn driver_fails_when_reconnecting_gives_up() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.set_backoff(Backoff {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(10),
+        max_retries: Some(2),
+        ..Default::default()
+    });
+
+    let driver = d.connect_driven().await?;
+    t.set_offline(true);
+
+    let err = timeout(WAIT, driver.run()).await?.unwrap_err();
+    assert!(matches!(err, DeviceError::CouldNotConnect(_)));
+    assert!(matches!(
+        d.connection_state(),
+        ConnectionState::Disconnected
+    ));
+
+    // The connection is gone by the time `run` returns
+    t.set_offline(false);
+    let driver = d.connect_driven().await?;
+    assert!(d.connection_state().is_connected());
+    d.disconnect().await?;
+    assert!(timeout(WAIT, driver.run()).await?.is_ok());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn dropping_the_driver_disconnects() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+
+    drop(d.connect_driven().await?);
+    assert!(matches!(
+        d.connection_state(),
+        ConnectionState::Disconnected
+    ));
+    assert!(d.input_banks().is_err());
+
+    d.connect().await?;
+    assert!(d.connection_state().is_connected());
+    d.disconnect().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn nothing_is_polled_until_the_driver_runs() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+
+    let driver = d.connect_driven().await?;
+    let mut updates = d.updates()?;
+
+    t.push("ext/ibank/0/ch/0/name", "Kick");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(updates.try_recv().is_err());
+
+    // Connecting twice fails the same way as with `connect`
+    assert!(d.connect().await.is_err());
+
+    d.disconnect().await?;
+    assert!(timeout(WAIT, driver.run()).await?.is_ok());
+
+    Ok(())
+}
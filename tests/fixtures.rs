@@ -0,0 +1,271 @@
+//! Bank building against the synthetic dumps in `tests/fixtures/`, see the README there.
+//! They check the parser against the published layouts, not against real devices.
+
+use dashmap::DashMap;
+use motu_avb_api::extchannel::{self, ChannelBank, ChannelBankType, Trim};
+use motu_avb_api::{KeyPath, Value};
+use serde_json::Value as SerdeValue;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+struct Fixture {
+    cache: Arc<DashMap<KeyPath, Value>>,
+    inputs: DashMap<u32, ChannelBank>,
+    outputs: DashMap<u32, ChannelBank>,
+}
+
+fn load(json_data: &str) -> Fixture {
+    let raw: HashMap<String, SerdeValue> = serde_json::from_str(json_data).unwrap();
+    let cache = Arc::new(DashMap::new());
+
+    for (k, v) in raw.into_iter() {
+        let v = Value::try_from(v)
+            .and_then(|v| v.decode(&k))
+            .unwrap_or_else(|e| panic!("could not decode {}: {}", k, e));
+        cache.insert(k.parse().unwrap(), v);
+    }
+
+    Fixture {
+        inputs: extchannel::build("ibank", cache.clone()).unwrap(),
+        outputs: extchannel::build("obank", cache.clone()).unwrap(),
+        cache,
+    }
+}
+
+impl Fixture {
+    fn bank(&self, t: ChannelBankType, name: &str) -> ChannelBank {
+        let banks = match t {
+            ChannelBankType::Input => &self.inputs,
+            ChannelBankType::Output => &self.outputs,
+        };
+
+        banks
+            .iter()
+            .find(|b| b.name.as_deref() == Some(name))
+            .unwrap_or_else(|| panic!("no bank named {}", name))
+            .clone()
+    }
+
+    fn input(&self, name: &str) -> ChannelBank {
+        self.bank(ChannelBankType::Input, name)
+    }
+
+    fn output(&self, name: &str) -> ChannelBank {
+        self.bank(ChannelBankType::Output, name)
+    }
+
+    /// Checks the invariants every bank of every model should hold
+    fn check_banks(&self, inputs: usize, outputs: usize) {
+        assert_eq!(self.inputs.len(), inputs);
+        assert_eq!(self.outputs.len(), outputs);
+
+        for (t, banks) in [
+            (ChannelBankType::Input, &self.inputs),
+            (ChannelBankType::Output, &self.outputs),
+        ] {
+            for b in banks.iter() {
+                assert_eq!(b.t, t);
+                assert_eq!(b.index, *b.key());
+                assert!(b.name.is_some());
+                assert!(b.num_channels <= b.max_channels);
+                assert_eq!(b.channels.len() as u32, b.max_channels);
+
+                for (i, ch) in b.channels.iter() {
+                    assert_eq!(ch.index, *i);
+                    assert!(ch.default_name.is_some());
+                }
+            }
+        }
+    }
+}
+
+fn trim_range(b: &ChannelBank, ch: u32) -> (i32, i32) {
+    match b.channels[&ch].trim.as_ref().unwrap() {
+        Trim::Mono(t) => t.trim_range,
+        Trim::Stereo(t) => t.trim_range,
+    }
+}
+
+fn is_stereo_trim(b: &ChannelBank, ch: u32) -> bool {
+    matches!(b.channels[&ch].trim, Some(Trim::Stereo(_)))
+}
+
+#[test]
+fn motu_624() {
+    let f = load(include_str!("fixtures/624.json"));
+    f.check_banks(5, 6);
+
+    let mic = f.input("Mic In");
+    assert_eq!(mic.num_channels, 2);
+    assert_eq!(trim_range(&mic, 0), (0, 53));
+    assert_eq!(mic.channels[&0].phantom_power, Some(false));
+    assert_eq!(mic.channels[&1].connection, Some(true));
+    assert_eq!(mic.channels[&0].pad, None);
+    assert_eq!(mic.channels[&0].default_name.as_deref(), Some("Mic 1"));
+
+    assert_eq!(trim_range(&f.input("Line In"), 3), (-96, 22));
+    assert_eq!(f.input("Computer In").num_channels, 24);
+
+    let main = f.output("Main Out");
+    assert_eq!(main.channels[&1].src.as_deref(), Some("0:1"));
+    assert_eq!(trim_range(&main, 0), (-127, 0));
+
+    let phones = f.output("Phones");
+    assert!(is_stereo_trim(&phones, 0));
+    assert_eq!(phones.channels[&1].trim, None);
+    assert_eq!(phones.channels[&0].connection, Some(true));
+}
+
+#[test]
+fn motu_8a() {
+    let f = load(include_str!("fixtures/8a.json"));
+    f.check_banks(4, 5);
+
+    // Running at 96k so the optical banks are halved
+    let optical = f.input("Optical In A");
+    assert_eq!(optical.num_channels, 4);
+    assert_eq!(optical.max_channels, 8);
+    assert_eq!(optical.currenty_active_channels, 4);
+    assert_eq!(optical.smux.as_deref(), Some("adat"));
+
+    let analog = f.input("Analog In");
+    assert_eq!(analog.num_channels, 8);
+    assert_eq!(analog.channels[&0].phantom_power, None);
+    assert_eq!(f.output("Optical Out B").smux.as_deref(), Some("adat"));
+}
+
+#[test]
+fn motu_1248() {
+    let f = load(include_str!("fixtures/1248.json"));
+    f.check_banks(10, 11);
+
+    let mic = f.input("Mic In");
+    assert_eq!(mic.num_channels, 4);
+    assert_eq!(mic.channels[&3].pad, Some(false));
+    assert_eq!(mic.channels[&3].phase, Some(false));
+    assert_eq!(mic.channels[&3].phantom_power, Some(false));
+
+    let line = f.input("Line In");
+    assert_eq!(line.channels[&0].connection, Some(true));
+    assert_eq!(line.channels[&7].connection, Some(false));
+
+    let optical = f.input("Optical In B");
+    assert_eq!(optical.smux.as_deref(), Some("toslink"));
+    assert_eq!(optical.num_channels, 2);
+
+    assert_eq!(f.output("Line Out").num_channels, 12);
+    assert!(is_stereo_trim(&f.output("Phones B"), 0));
+    assert_eq!(f.input("AVB Stream In 4").num_channels, 8);
+    assert_eq!(f.input("Computer In").num_channels, 32);
+}
+
+#[test]
+fn motu_16a() {
+    let f = load(include_str!("fixtures/16a.json"));
+    f.check_banks(4, 5);
+
+    assert_eq!(f.input("Analog In").num_channels, 16);
+    assert_eq!(trim_range(&f.output("Analog Out"), 15), (-24, 0));
+    assert_eq!(f.input("Optical In B").smux.as_deref(), Some("adat"));
+    assert_eq!(
+        f.output("Computer Out").channels[&5].src.as_deref(),
+        Some("0:1")
+    );
+}
+
+#[test]
+fn motu_8m() {
+    let f = load(include_str!("fixtures/8m.json"));
+    f.check_banks(4, 5);
+
+    let mic = f.input("Mic In");
+    assert_eq!(mic.num_channels, 8);
+    assert!(mic.channels.values().all(|c| c.phantom_power == Some(true)));
+    assert!(mic.channels.values().all(|c| c.pad == Some(false)));
+    assert_eq!(mic.channels[&3].connection, Some(true));
+    assert_eq!(mic.channels[&4].connection, Some(false));
+
+    assert_eq!(
+        f.cache
+            .get("avb/0001f2fffe00008b/cfg/0/current_sampling_rate")
+            .map(|v| v.value().clone()),
+        Some(Value::Int(44100))
+    );
+}
+
+#[test]
+fn motu_828es() {
+    let f = load(include_str!("fixtures/828es.json"));
+    f.check_banks(6, 7);
+
+    // The manual says toslink or adat but this is what the 828es reports
+    assert_eq!(f.input("Optical In A").smux.as_deref(), Some("standard"));
+    assert_eq!(f.output("Optical Out B").smux.as_deref(), Some("standard"));
+
+    assert_eq!(f.input("S/PDIF In").num_channels, 2);
+    assert_eq!(f.input("Mic In").channels[&1].phantom_power, Some(false));
+    assert_eq!(f.input("Mic In").channels[&1].connection, None);
+}
+
+#[test]
+fn motu_stage_b16() {
+    let f = load(include_str!("fixtures/stage-b16.json"));
+    f.check_banks(5, 6);
+
+    let mic = f.input("Mic In");
+    assert_eq!(mic.num_channels, 16);
+    assert_eq!(mic.channels[&8].connection, Some(true));
+    assert_eq!(mic.channels[&9].connection, Some(false));
+    assert_eq!(trim_range(&mic, 15), (0, 53));
+
+    assert_eq!(f.output("Line Out").num_channels, 12);
+    assert!(f.inputs.iter().all(|b| b.smux.is_none()));
+}
+
+#[test]
+fn motu_112d() {
+    let f = load(include_str!("fixtures/112d.json"));
+    f.check_banks(12, 12);
+
+    let madi = f.input("MADI In 1");
+    assert_eq!(madi.num_channels, 64);
+    assert!(madi.channels.values().all(|c| c.trim.is_none()));
+
+    assert_eq!(f.input("AES In").num_channels, 24);
+    assert_eq!(
+        f.output("AES Out").channels[&23].src.as_deref(),
+        Some("0:23")
+    );
+    assert_eq!(f.input("AVB Stream In 8").num_channels, 8);
+}
+
+#[test]
+fn mixer_values_decode() {
+    let f = load(include_str!("fixtures/1248.json"));
+
+    assert_eq!(
+        f.cache
+            .get("mix/chan/1/matrix/fader")
+            .map(|v| v.value().clone()),
+        Some(Value::Float(1.0))
+    );
+    assert_eq!(
+        f.cache
+            .get("mix/chan/0/matrix/mute")
+            .map(|v| v.value().clone()),
+        Some(Value::Int(0))
+    );
+}
+
+#[test]
+fn truncated_bank_keys_are_errors() -> anyhow::Result<()> {
+    let cache = Arc::new(DashMap::new());
+    cache.insert("ext/ibank/0".parse()?, Value::String("Mic In".into()));
+    assert!(extchannel::build("ibank", cache.clone()).is_err());
+
+    cache.clear();
+    cache.insert("ext/ibank/0/ch".parse()?, Value::Int(1));
+    assert!(extchannel::build("ibank", cache).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use dashmap::DashMap;
+use motu_avb_api::extchannel;
+use motu_avb_api::{KeyPath, KeyPathError, Value};
+use std::sync::Arc;
+
+#[test]
+fn parses_keys() -> anyhow::Result<()> {
+    let k: KeyPath = "ext/obank/2/ch/5/trim".parse()?;
+    assert_eq!(k.segment(1), Some("obank"));
+    assert_eq!(k.index(2), Some(2));
+    assert_eq!(k.index(1), None);
+    assert_eq!(k.segments().count(), 6);
+    assert_eq!(k.to_string(), "ext/obank/2/ch/5/trim");
+
+    // Surrounding slashes are dropped
+    assert_eq!("/ext/clockSource/".parse::<KeyPath>()?, "ext/clockSource");
+
+    assert!(matches!("".parse::<KeyPath>(), Err(KeyPathError::Empty)));
+    assert!(matches!("/".parse::<KeyPath>(), Err(KeyPathError::Empty)));
+    assert!(matches!(
+        "ext//ch".parse::<KeyPath>(),
+        Err(KeyPathError::EmptySegment(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn composes_keys() -> anyhow::Result<()> {
+    let bank: KeyPath = "ext/ibank/0".parse()?;
+    let trim = bank.child("ch/3/trim")?;
+    assert_eq!(trim, "ext/ibank/0/ch/3/trim");
+    assert_eq!(trim.parent().unwrap(), "ext/ibank/0/ch/3");
+    assert!(KeyPath::from(3).parent().is_none());
+    assert!(bank.child("").is_err());
+
+    assert!(trim.starts_with(&bank));
+    assert!(bank.starts_with(&bank));
+    assert!(!trim.starts_with(&"ext/ibank/01".parse()?));
+    assert_eq!(trim.strip_prefix(&bank).unwrap(), "ch/3/trim");
+    assert_eq!(bank.join(&KeyPath::from(7)), "ext/ibank/0/7");
+
+    Ok(())
+}
+
+#[test]
+fn serializes_as_a_string() -> anyhow::Result<()> {
+    let k: KeyPath = "mix/chan/0/matrix/fader".parse()?;
+    assert_eq!(serde_json::to_string(&k)?, "\"mix/chan/0/matrix/fader\"");
+    assert_eq!(
+        serde_json::from_str::<KeyPath>("\"mix/chan/0\"")?,
+        "mix/chan/0"
+    );
+    assert!(serde_json::from_str::<KeyPath>("\"mix//0\"").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn banks_build_requests_from_their_path() -> anyhow::Result<()> {
+    let cache: DashMap<KeyPath, Value> = DashMap::new();
+    cache.insert("ext/ibank/1/ch/2/pad".parse()?, Value::Int(0));
+    cache.insert("ext/ibank/1/ch/2/trim".parse()?, Value::Int(0));
+    cache.insert(
+        "ext/ibank/1/ch/2/trimRange".parse()?,
+        Value::Pair(vec!["-10".into(), "10".into()]),
+    );
+    let cache = Arc::new(cache);
+
+    // The cache is keyed by KeyPath but can be looked up with a &str
+    assert_eq!(
+        cache.get("ext/ibank/1/ch/2/pad").map(|v| v.clone()),
+        Some(Value::Int(0))
+    );
+
+    let banks = extchannel::build("ibank", cache)?;
+    let bank = banks.get(&1).unwrap();
+
+    assert_eq!(bank.set_name("Mics").key, "ext/ibank/1/name");
+    assert_eq!(
+        bank.set_channel_name(2, "Kick").key,
+        "ext/ibank/1/ch/2/name"
+    );
+
+    let pad = bank.set_pad(2, true).unwrap();
+    assert_eq!(pad.key, "ext/ibank/1/ch/2/pad");
+    assert_eq!(pad.val, Value::Int(1));
+
+    let trim = bank.set_channel_trim(2, 5).unwrap();
+    assert_eq!(trim.key, "ext/ibank/1/ch/2/trim");
+    assert_eq!(trim.val, Value::Int(5));
+
+    assert!(bank.set_phase(2, true).is_none());
+    assert!(bank.set_pad(3, true).is_none());
+
+    Ok(())
+}
@@ -0,0 +1,303 @@
+mod common;
+
+use common::{next_external, wait_for_state, wait_until};
+use motu_avb_api::mock::MockServer;
+use motu_avb_api::{Backoff, ConnectionState, Device, Value};
+use std::time::Duration;
+
+const DATASTORE: &str = r#"{
+    "uid": "0001f2fffe000000",
+    "ext/ibank/0/name": "Mic In",
+    "ext/ibank/0/numCh": 2,
+    "ext/ibank/0/maxCh": 2,
+    "ext/ibank/0/userCh": 2,
+    "ext/ibank/0/calcCh": 2,
+    "ext/ibank/0/ch/0/name": "",
+    "ext/ibank/0/ch/0/defaultName": "Mic 1",
+    "ext/ibank/0/ch/0/trim": 0,
+    "ext/ibank/0/ch/0/trimRange": "0:53",
+    "ext/ibank/0/ch/0/48V": 0,
+    "ext/ibank/0/ch/1/name": "Vox",
+    "ext/ibank/0/ch/1/defaultName": "Mic 2",
+    "ext/ibank/0/ch/1/trim": 10,
+    "ext/ibank/0/ch/1/trimRange": "0:53",
+    "ext/obank/0/name": "Main Out",
+    "ext/obank/0/numCh": 2,
+    "ext/obank/0/maxCh": 2,
+    "ext/obank/0/userCh": 2,
+    "ext/obank/0/calcCh": 2,
+    "ext/obank/0/ch/0/name": "",
+    "ext/obank/0/ch/0/defaultName": "Main L",
+    "ext/obank/0/ch/0/trim": 0,
+    "ext/obank/0/ch/0/trimRange": "-127:0",
+    "ext/obank/0/ch/1/name": "",
+    "ext/obank/0/ch/1/defaultName": "Main R",
+    "ext/obank/0/ch/1/trim": 0,
+    "ext/obank/0/ch/1/trimRange": "-127:0"
+}"#;
+
+#[tokio::test]
+async fn connect_builds_channel_banks() -> anyhow::Result<()> {
+    let mock = MockServer::from_json(DATASTORE).await?;
+    let d = mock.device();
+    d.connect().await?;
+
+    assert!(d.connection_state().is_connected());
+    assert_eq!(
+        d.get_value("ext/ibank/0/name"),
+        Some(Value::String("Mic In".into()))
+    );
+
+    let inputs = d.input_banks()?;
+    let bank = inputs.get(&0).unwrap();
+    assert_eq!(bank.name.as_deref(), Some("Mic In"));
+    assert_eq!(bank.num_channels, 2);
+    assert_eq!(bank.channels[&1].name.as_deref(), Some("Vox"));
+
+    let outputs = d.output_banks()?;
+    assert_eq!(outputs.get(&0).unwrap().name.as_deref(), Some("Main Out"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn set_patches_the_datastore() -> anyhow::Result<()> {
+    let mock = MockServer::from_json(DATASTORE).await?;
+    let d = mock.device();
+    d.connect().await?;
+
+    let req = d
+        .output_banks()?
+        .get(&0)
+        .unwrap()
+        .set_channel_trim(0, -30)
+        .unwrap();
+    d.set(req).await?;
+
+    assert_eq!(mock.get("ext/obank/0/ch/0/trim"), Some((-30).into()));
+    assert_eq!(mock.patches().len(), 1);
+    assert_eq!(d.get_value("ext/obank/0/ch/0/trim"), Some(Value::Int(-30)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn set_confirmed_reads_the_key_back() -> anyhow::Result<()> {
+    let mock = MockServer::from_json(DATASTORE).await?;
+    let d = mock.device();
+    d.connect().await?;
+
+    let req = d
+        .input_banks()?
+        .get(&0)
+        .unwrap()
+        .set_channel_trim(1, 20)
+        .unwrap();
+    let v = d.set_confirmed(req, Duration::from_secs(1)).await?;
+
+    assert_eq!(v, Value::Int(20));
+    assert_eq!(mock.get("ext/ibank/0/ch/1/trim"), Some(20.into()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn builder_configures_the_http_transport() -> anyhow::Result<()> {
+    let mock = MockServer::from_json(DATASTORE).await?;
+    let addr = mock.addr();
+
+    let d = Device::builder(&addr.ip().to_string(), addr.port())
+        .client_id(42)
+        .user_agent("motu-test")
+        .request_timeout(Duration::from_secs(1))
+        .long_poll_timeout(Duration::from_millis(200))
+        .build()?;
+    d.connect().await?;
+    assert_eq!(d.client_id(), 42);
+
+    // Long polls that time out are not errors
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(d.connection_state().is_connected());
+
+    d.set_keys(&[("ext/ibank/0/ch/0/trim", Value::Int(12))])
+        .await?;
+    assert_eq!(mock.patches()[0].client, Some(42));
+
+    let d = Device::builder(&addr.ip().to_string(), addr.port())
+        .base_path("/motu")
+        .build()?;
+    assert!(d.connect().await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn external_changes_are_long_polled() -> anyhow::Result<()> {
+    let mock = MockServer::from_json(DATASTORE).await?;
+    let d = mock.device();
+    d.connect().await?;
+
+    let mut updates = d.updates()?;
+    mock.push("ext/ibank/0/ch/0/name", "Kick");
+
+    let v = next_external(&mut updates, "ext/ibank/0/ch/0/name").await;
+    assert_eq!(v, Value::String("Kick".into()));
+
+    // Give the mapping task a moment to apply the change to the bank
+    wait_until(|| {
+        d.input_banks().unwrap().get(&0).unwrap().channels[&0]
+            .name
+            .as_deref()
+            == Some("Kick")
+    })
+    .await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnects_after_outage() -> anyhow::Result<()> {
+    let mock = MockServer::from_json(DATASTORE).await?;
+    let d = mock.device();
+    d.set_backoff(Backoff {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(50),
+        ..Default::default()
+    });
+    d.connect().await?;
+
+    let mut updates = d.updates()?;
+
+    mock.set_offline(true);
+    wait_for_state(&d, |s| matches!(s, ConnectionState::Degraded(_))).await;
+    mock.push("ext/obank/0/name", "Monitors");
+    mock.set_offline(false);
+
+    wait_for_state(&d, |s| s.is_connected()).await;
+    let v = next_external(&mut updates, "ext/obank/0/name").await;
+    assert_eq!(v, Value::String("Monitors".into()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn disconnect_allows_reconnecting() -> anyhow::Result<()> {
+    let mock = MockServer::from_json(DATASTORE).await?;
+    let d = mock.device();
+    let handle = d.clone();
+
+    d.connect().await?;
+    assert!(handle.connection_state().is_connected());
+
+    handle.disconnect().await?;
+    assert_eq!(d.connection_state(), ConnectionState::Disconnected);
+    assert!(d.updates().is_err());
+    assert!(d.input_banks().is_err());
+
+    d.connect().await?;
+    assert!(handle.input_banks().is_ok());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn recorded_session_replays_to_the_same_state() -> anyhow::Result<()> {
+    use motu_avb_api::session::{Recorder, ReplayPace, Session};
+    use std::collections::BTreeMap;
+
+    let path = std::env::temp_dir().join(format!("motu-session-{}.jsonl", std::process::id()));
+
+    let mock = MockServer::from_json(DATASTORE).await?;
+    let d = mock.device();
+    d.start_recording(Recorder::create(&path)?);
+    d.connect().await?;
+
+    let mut updates = d.updates()?;
+    mock.push("ext/ibank/0/ch/1/name", "Snare");
+    next_external(&mut updates, "ext/ibank/0/ch/1/name").await;
+
+    let req = d
+        .output_banks()?
+        .get(&0)
+        .unwrap()
+        .set_channel_trim(1, -12)
+        .unwrap();
+    d.set(req).await?;
+    d.stop_recording();
+
+    let session = Session::load(&path)?;
+    std::fs::remove_file(&path)?;
+    assert!(session.events.len() >= 3);
+
+    let replayed = mock.device();
+    replayed.replay(&session, ReplayPace::Immediate).await?;
+
+    for key in [
+        "ext/ibank/0/ch/1/name",
+        "ext/obank/0/ch/0/trim",
+        "ext/obank/0/ch/1/trim",
+    ] {
+        assert_eq!(replayed.get_value(key), d.get_value(key), "{}", key);
+    }
+
+    let replayed_banks: BTreeMap<_, _> = replayed
+        .input_banks()?
+        .iter()
+        .map(|b| (*b.key(), b.value().clone()))
+        .collect();
+    let expected: BTreeMap<_, _> = motu_avb_api::extchannel::build("ibank", d.get())?
+        .into_iter()
+        .collect();
+    assert_eq!(replayed_banks, expected);
+    assert_eq!(
+        replayed.input_banks()?.get(&0).unwrap().channels[&1]
+            .name
+            .as_deref(),
+        Some("Snare")
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn recording_while_connected_starts_with_the_whole_cache() -> anyhow::Result<()> {
+    use motu_avb_api::session::{Recorder, ReplayPace, Session, SessionEventKind};
+    use std::collections::BTreeMap;
+
+    let path = std::env::temp_dir().join(format!(
+        "motu-session-connected-{}.jsonl",
+        std::process::id()
+    ));
+
+    let mock = MockServer::from_json(DATASTORE).await?;
+    let d = mock.device();
+    d.connect().await?;
+
+    let mut updates = d.updates()?;
+    d.start_recording(Recorder::create(&path)?);
+    mock.push("ext/ibank/0/ch/1/name", "Snare");
+    next_external(&mut updates, "ext/ibank/0/ch/1/name").await;
+    d.stop_recording();
+
+    let session = Session::load(&path)?;
+    std::fs::remove_file(&path)?;
+    match &session.events[0].kind {
+        SessionEventKind::Poll { body, .. } => assert_eq!(body.len(), d.get().len()),
+        e => panic!("session starts with {:?}", e),
+    }
+
+    let replayed = mock.device();
+    replayed.replay(&session, ReplayPace::Immediate).await?;
+
+    let replayed_banks: BTreeMap<_, _> = replayed
+        .input_banks()?
+        .iter()
+        .map(|b| (*b.key(), b.value().clone()))
+        .collect();
+    let expected: BTreeMap<_, _> = motu_avb_api::extchannel::build("ibank", d.get())?
+        .into_iter()
+        .collect();
+    assert_eq!(replayed_banks, expected);
+
+    Ok(())
+}
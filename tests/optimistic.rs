@@ -0,0 +1,131 @@
+mod common;
+
+use common::{transport, trim, unresponsive, TRIM};
+use motu_avb_api::device::DeviceError;
+use motu_avb_api::{Device, KeyPath, Rollback, Update, Value, WriteMode};
+use serde_json::json;
+use std::time::Duration;
+
+fn optimistic(d: &Device) {
+    d.set_write_mode(WriteMode::Optimistic {
+        timeout: Duration::from_millis(100),
+    });
+}
+
+#[tokio::test]
+async fn optimistic_writes_are_confirmed() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+    optimistic(&d);
+
+    let mut updates = d.updates()?;
+    d.set(trim(-20)).await?;
+
+    assert_eq!(
+        updates.try_recv()?,
+        Update::Internal(TRIM.parse()?, Value::Int(-20))
+    );
+    assert!(updates.try_recv().is_err());
+    assert!(!d.is_pending(TRIM));
+    assert_eq!(d.get_value(TRIM), Some(Value::Int(-20)));
+    assert_eq!(t.get(TRIM), Some(json!(-20)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_writes_roll_back() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+    optimistic(&d);
+
+    let before = d.get_value(TRIM);
+    let mut updates = d.updates()?;
+
+    t.set_offline(true);
+    assert!(matches!(
+        d.set(trim(-20)).await,
+        Err(DeviceError::RolledBack(_))
+    ));
+
+    assert_eq!(d.get_value(TRIM), before);
+    assert!(!d.is_pending(TRIM));
+
+    assert_eq!(
+        updates.recv().await?,
+        Update::Internal(TRIM.parse()?, Value::Int(-20))
+    );
+    match updates.recv().await? {
+        Update::Rollback(Rollback {
+            key,
+            attempted,
+            restored,
+            ..
+        }) => {
+            assert_eq!(key, TRIM);
+            assert_eq!(attempted, Value::Int(-20));
+            assert_eq!(restored, before);
+        }
+        u => panic!("expected a rollback, got {:?}", u),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn writes_roll_back_on_timeout() -> anyhow::Result<()> {
+    let t = transport();
+    let d = unresponsive(&t);
+    d.connect().await?;
+    optimistic(&d);
+
+    let before = d.get_value(TRIM);
+
+    let write = tokio::spawn({
+        let d = d.clone();
+        async move { d.set(trim(-20)).await }
+    });
+
+    // Applied and pending while the device sits on it
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(d.get_value(TRIM), Some(Value::Int(-20)));
+    assert_eq!(d.pending_keys(), vec![TRIM.parse::<KeyPath>()?]);
+
+    let res = write.await?;
+    assert!(matches!(res, Err(DeviceError::RolledBack(e)) if matches!(*e, DeviceError::Timeout)));
+    assert_eq!(d.get_value(TRIM), before);
+    assert!(d.pending_keys().is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn device_changes_win_over_rollbacks() -> anyhow::Result<()> {
+    let t = transport();
+    let d = unresponsive(&t);
+    d.connect().await?;
+    optimistic(&d);
+
+    let write = tokio::spawn({
+        let d = d.clone();
+        async move { d.set(trim(-20)).await }
+    });
+
+    // Someone turns the knob while our write is stuck
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let mut updates = d.updates()?;
+    t.push(TRIM, -5);
+    assert_eq!(
+        updates.recv().await?,
+        Update::External(TRIM.parse()?, Value::Int(-5))
+    );
+
+    assert!(matches!(write.await?, Err(DeviceError::RolledBack(_))));
+    assert_eq!(d.get_value(TRIM), Some(Value::Int(-5)));
+    assert!(d.pending_keys().is_empty());
+    assert!(updates.try_recv().is_err());
+
+    Ok(())
+}
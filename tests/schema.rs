@@ -0,0 +1,137 @@
+use motu_avb_api::device::DeviceError;
+use motu_avb_api::schema::{Schema, SchemaError, Unit, ValueType};
+use motu_avb_api::transport::MemoryTransport;
+use motu_avb_api::Value;
+use serde_json::{json, Value as SerdeValue};
+use std::collections::HashMap;
+
+const FIXTURES: [&str; 8] = [
+    include_str!("fixtures/624.json"),
+    include_str!("fixtures/8a.json"),
+    include_str!("fixtures/1248.json"),
+    include_str!("fixtures/16a.json"),
+    include_str!("fixtures/8m.json"),
+    include_str!("fixtures/828es.json"),
+    include_str!("fixtures/stage-b16.json"),
+    include_str!("fixtures/112d.json"),
+];
+
+#[test]
+fn every_fixture_key_is_described() {
+    for f in FIXTURES {
+        let raw: HashMap<String, SerdeValue> = serde_json::from_str(f).unwrap();
+        for k in raw.keys() {
+            assert!(
+                Schema::motu().describe(k).is_some(),
+                "{} is not described",
+                k
+            );
+        }
+    }
+}
+
+#[test]
+fn describes_keys() {
+    let s = Schema::motu();
+
+    let trim = s.describe("ext/ibank/0/ch/1/trim").unwrap();
+    assert_eq!(trim.value_type, ValueType::Int);
+    assert_eq!(trim.unit, Some(Unit::Decibel));
+    assert!(!trim.read_only);
+
+    let rate = s
+        .describe("avb/0001f2fffe00624a/cfg/0/current_sampling_rate")
+        .unwrap();
+    assert_eq!(rate.unit, Some(Unit::Hertz));
+
+    assert!(s.describe("ext/obank/2/ch/0/connection").unwrap().read_only);
+    assert!(s.describe("not/a/key").is_none());
+    assert!(s.iter().count() > 30);
+
+    let fader = s.describe("mix/chan/3/matrix/fader").unwrap().to_string();
+    assert_eq!(
+        fader,
+        "mix/*/*/matrix/fader (real, gain, 0..=4): Fader level"
+    );
+}
+
+#[test]
+fn validates_writes() {
+    let s = Schema::motu();
+
+    assert!(s.validate("ext/ibank/0/ch/0/trim", &Value::Int(20)).is_ok());
+    assert!(s.validate("ext/ibank/0/ch/0/48V", &Value::Int(1)).is_ok());
+    assert!(s
+        .validate("mix/chan/0/matrix/fader", &Value::Float(0.5))
+        .is_ok());
+    assert!(s.validate("not/a/key", &Value::Bool(true)).is_ok());
+
+    assert!(matches!(
+        s.validate("ext/ibank/0/ch/0/connection", &Value::Int(1)),
+        Err(SchemaError::ReadOnly(_))
+    ));
+    assert!(matches!(
+        s.validate("ext/ibank/0/ch/0/48V", &Value::Bool(true)),
+        Err(SchemaError::WrongType { .. })
+    ));
+    assert!(matches!(
+        s.validate("ext/ibank/0/ch/0/48V", &Value::Int(2)),
+        Err(SchemaError::WrongType { .. })
+    ));
+    assert!(matches!(
+        s.validate("mix/chan/0/matrix/fader", &Value::Float(4.5)),
+        Err(SchemaError::NotAllowed { .. })
+    ));
+    assert!(matches!(
+        s.validate(
+            "avb/0001f2fffe00624a/cfg/0/current_sampling_rate",
+            &Value::Int(22050)
+        ),
+        Err(SchemaError::NotAllowed { .. })
+    ));
+}
+
+#[test]
+fn decode_follows_the_schema() {
+    let decode = |k: &str, v: SerdeValue| Value::try_from(v).and_then(|v| v.decode(k)).unwrap();
+
+    assert_eq!(
+        decode("avb/0001f2fffe00624a/entity_name", json!("Studio: A")),
+        Value::String("Studio: A".into())
+    );
+    assert_eq!(
+        decode(
+            "avb/0001f2fffe00624a/cfg/0/sample_rates",
+            json!("44100:48000")
+        ),
+        Value::Pair(vec!["44100".into(), "48000".into()])
+    );
+    assert!(matches!(
+        decode("mix/chan/0/label", json!("Studio: A")),
+        Value::Raw(_)
+    ));
+}
+
+#[tokio::test]
+async fn set_keys_rejects_invalid_writes() -> anyhow::Result<()> {
+    let t = MemoryTransport::from_json(include_str!("fixtures/624.json"))?;
+    let d = t.device();
+    d.connect().await?;
+
+    let res = d
+        .set_keys(&[
+            ("ext/ibank/0/ch/0/trim", Value::Int(10)),
+            ("ext/ibank/0/ch/0/connection", Value::Int(0)),
+        ])
+        .await;
+    assert!(matches!(
+        res,
+        Err(DeviceError::SchemaError(SchemaError::ReadOnly(_)))
+    ));
+
+    // Nothing was sent
+    assert!(t.patches().is_empty());
+    assert_eq!(t.get("ext/ibank/0/ch/0/trim"), Some(json!(0)));
+
+    Ok(())
+}
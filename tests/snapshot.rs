@@ -0,0 +1,212 @@
+mod common;
+
+use common::{transport, wait_until};
+use motu_avb_api::device::DeviceError;
+use motu_avb_api::transport::MemoryTransport;
+use motu_avb_api::{Device, KeyPath, Snapshot, Value};
+use serde_json::json;
+
+/// Changes a key on the device and waits for the long poll to bring it into the cache
+async fn front_panel(t: &MemoryTransport, d: &Device, key: &str, value: Value) {
+    t.push(key, value.encode());
+    wait_until(|| d.get_value(key) == Some(value.clone())).await;
+}
+
+#[tokio::test]
+async fn snapshots_round_trip_through_json_and_toml() -> anyhow::Result<()> {
+    let t = transport();
+    t.set_api_version("0.0.0");
+    let d = t.device();
+    d.connect().await?;
+
+    let s = d.snapshot().await?;
+    assert_eq!(s.uid, "0001f2fffe00624a");
+    assert_eq!(s.model.as_deref(), Some("624"));
+    assert_eq!(s.api_version, "0.0.0");
+    assert!(s.timestamp > 0);
+    assert_eq!(s.values.len(), d.get().len());
+    assert_eq!(s.values["ext/obank/0/ch/0/trim"], json!(0));
+
+    assert_eq!(Snapshot::from_json(&s.to_json()?)?, s);
+    assert_eq!(Snapshot::from_toml(&s.to_toml()?)?, s);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn raw_objects_and_arrays_round_trip_through_toml() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+
+    // Things the datastore might send that no encoding covers, between plain keys
+    front_panel(&t, &d, "ext/a/list", Value::try_from(json!([1, 2, 3]))?).await;
+    front_panel(
+        &t,
+        &d,
+        "ext/a/object",
+        Value::try_from(json!({"x": 1, "y": {"z": [1, 2]}, "zz": "three"}))?,
+    )
+    .await;
+    front_panel(
+        &t,
+        &d,
+        "ext/a/tables",
+        Value::try_from(json!([{"x": 1}, {"x": 2}]))?,
+    )
+    .await;
+
+    let s = d.snapshot().await?;
+    assert!(matches!(d.get_value("ext/a/object"), Some(Value::Raw(_))));
+    assert_eq!(
+        s.values["ext/a/object"],
+        json!({"x": 1, "y": {"z": [1, 2]}, "zz": "three"})
+    );
+    assert!(s.values.keys().any(|k| k.as_str() > "ext/a/tables"));
+
+    assert_eq!(Snapshot::from_toml(&s.to_toml()?)?, s);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn restore_writes_only_what_changed() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+
+    let s = d.snapshot().await?;
+
+    // Nothing changed, nothing to send
+    let report = d.restore(&s, |_| true).await?;
+    assert!(report.written.is_empty());
+    assert!(t.patches().is_empty());
+
+    front_panel(&t, &d, "ext/obank/0/ch/0/trim", Value::Int(-20)).await;
+    front_panel(
+        &t,
+        &d,
+        "ext/ibank/0/ch/0/name",
+        Value::String("Kick".into()),
+    )
+    .await;
+    front_panel(&t, &d, "ext/clockLocked", Value::Bool(false)).await;
+
+    let report = d.restore(&s, |_| true).await?;
+    assert_eq!(
+        report.written,
+        vec![
+            "ext/ibank/0/ch/0/name".parse::<KeyPath>()?,
+            "ext/obank/0/ch/0/trim".parse()?,
+        ]
+    );
+    assert!(report.read_only.contains(&"ext/clockLocked".parse()?));
+    assert!(report.missing.is_empty());
+
+    let patches = t.patches();
+    assert_eq!(patches.len(), 1);
+    assert_eq!(patches[0].values.len(), 2);
+    assert_eq!(t.get("ext/obank/0/ch/0/trim"), Some(json!(0)));
+    assert_eq!(d.get_value("ext/obank/0/ch/0/trim"), Some(Value::Int(0)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn restore_honours_the_filter() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+
+    let mut s = d.snapshot().await?;
+    s.values.insert("ext/obank/9/ch/0/trim".into(), json!(-3));
+
+    front_panel(&t, &d, "ext/obank/0/ch/0/trim", Value::Int(-20)).await;
+    front_panel(
+        &t,
+        &d,
+        "ext/ibank/0/ch/0/name",
+        Value::String("Kick".into()),
+    )
+    .await;
+
+    let report = d
+        .restore(&s, |k| k.as_str().starts_with("ext/obank"))
+        .await?;
+    assert_eq!(
+        report.written,
+        vec!["ext/obank/0/ch/0/trim".parse::<KeyPath>()?]
+    );
+    assert_eq!(
+        report.missing,
+        vec!["ext/obank/9/ch/0/trim".parse::<KeyPath>()?]
+    );
+    assert_eq!(t.get("ext/ibank/0/ch/0/name"), Some(json!("Kick")));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn restore_writes_nothing_if_an_entry_is_bad() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+
+    // More than one batch of good changes ahead of the bad one
+    let mut s = d.snapshot().await?;
+    let names: Vec<String> = s
+        .values
+        .keys()
+        .filter(|k| k.starts_with("ext/") && k.contains("/ch/") && k.ends_with("/name"))
+        .cloned()
+        .collect();
+    assert!(names.len() > 64);
+    for (i, k) in names.into_iter().enumerate() {
+        s.values.insert(k, json!(format!("Mic {}", i)));
+    }
+    s.values
+        .insert("mix/chan/0/matrix/fader".into(), json!(9.0));
+
+    assert!(matches!(
+        d.restore(&s, |_| true).await,
+        Err(DeviceError::SchemaError(_))
+    ));
+    assert!(t.patches().is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn restore_refuses_snapshots_of_other_devices() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+
+    let mut s = d.snapshot().await?;
+    s.values.insert("ext/obank/0/ch/0/trim".into(), json!(-3));
+
+    let mut other = s.clone();
+    other.uid = "0001f2fffe00ffff".into();
+    assert!(matches!(
+        d.restore(&other, |_| true).await,
+        Err(DeviceError::SnapshotMismatch(..))
+    ));
+
+    let mut other = s.clone();
+    other.model = Some("8A".into());
+    assert!(matches!(
+        d.restore(&other, |_| true).await,
+        Err(DeviceError::SnapshotMismatch(..))
+    ));
+    assert!(t.patches().is_empty());
+
+    // Copying a configuration between units has to be asked for
+    let report = d.restore_unchecked(&other, |_| true).await?;
+    assert_eq!(
+        report.written,
+        vec!["ext/obank/0/ch/0/trim".parse::<KeyPath>()?]
+    );
+    assert_eq!(t.get("ext/obank/0/ch/0/trim"), Some(json!(-3)));
+
+    Ok(())
+}
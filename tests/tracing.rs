@@ -0,0 +1,74 @@
+mod common;
+
+use common::{transport, trim};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+/// Collects everything a subscriber writes
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Captured {
+    fn lines(&self) -> Vec<String> {
+        let buf = self.0.lock().unwrap();
+        String::from_utf8_lossy(&buf)
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+/// The first line mentioning `message` inside the device span
+fn event(lines: &[String], message: &str) -> String {
+    lines
+        .iter()
+        .find(|l| l.contains("device{uid=") && l.contains(&format!(": {}", message)))
+        .unwrap_or_else(|| panic!("no `{}` event in {:#?}", message, lines))
+        .clone()
+}
+
+#[tokio::test]
+async fn polls_and_patches_are_logged_in_the_device_span() -> anyhow::Result<()> {
+    let out = Captured::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .without_time()
+        .with_writer({
+            let out = out.clone();
+            move || out.clone()
+        })
+        .finish();
+    // The test runtime is single threaded, so the device's tasks log here too
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+    d.set(trim(-3)).await?;
+    d.disconnect().await?;
+
+    let lines = out.lines();
+
+    let poll = event(&lines, "poll");
+    assert!(poll.contains("DEBUG"));
+    assert!(poll.contains("status=\"changed\""));
+    assert!(poll.contains(&format!("keys={}", d.get().len())));
+
+    let patch = event(&lines, "patch");
+    assert!(patch.contains("DEBUG"));
+    assert!(patch.contains("keys=1"));
+    assert!(!lines.iter().any(|l| l.contains("patch failed")));
+
+    Ok(())
+}
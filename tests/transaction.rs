@@ -0,0 +1,104 @@
+mod common;
+
+use common::transport;
+use motu_avb_api::device::DeviceError;
+use motu_avb_api::schema::SchemaError;
+use motu_avb_api::{KeyPath, Value};
+use serde_json::json;
+
+#[tokio::test]
+async fn transaction_sends_one_patch() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+
+    let banks = d.input_banks()?;
+    let bank = banks.get(&0).unwrap();
+
+    let report = d
+        .transaction()
+        .push(bank.set_name("Preamps"))
+        .push(bank.set_channel_name(0, "Kick"))
+        .extend(bank.set_phantom_power(1, true))
+        .extend(bank.set_channel_trim(0, 10))
+        // Last write wins
+        .push(bank.set_channel_name(0, "Snare"))
+        .set(
+            "ext/ibank/0/ch/9/name".parse()?,
+            Value::String("Nope".into()),
+        )
+        .commit()
+        .await?;
+
+    let patches = t.patches();
+    assert_eq!(patches.len(), 1);
+    assert_eq!(patches[0].values.len(), 5);
+
+    assert_eq!(t.get("ext/ibank/0/name"), Some(json!("Preamps")));
+    assert_eq!(t.get("ext/ibank/0/ch/0/name"), Some(json!("Snare")));
+    assert_eq!(t.get("ext/ibank/0/ch/1/48V"), Some(json!(1)));
+    assert_eq!(t.get("ext/ibank/0/ch/0/trim"), Some(json!(10)));
+    assert_eq!(
+        d.get_value("ext/ibank/0/ch/0/name"),
+        Some(Value::String("Snare".into()))
+    );
+
+    assert_eq!(report.known.len(), 4);
+    assert_eq!(
+        report.unknown,
+        vec!["ext/ibank/0/ch/9/name".parse::<KeyPath>()?]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalid_transactions_send_nothing() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+
+    let mut tx = d.transaction();
+    tx.set("ext/ibank/0/ch/0/trim".parse()?, Value::Int(10))
+        .set("ext/ibank/0/ch/0/connection".parse()?, Value::Int(1));
+
+    assert!(matches!(
+        tx.commit().await,
+        Err(DeviceError::SchemaError(SchemaError::ReadOnly(_)))
+    ));
+    assert!(t.patches().is_empty());
+
+    // Left untouched on errors
+    assert_eq!(tx.len(), 2);
+    assert_eq!(tx.get("ext/ibank/0/ch/0/trim"), Some(&Value::Int(10)));
+
+    let mut empty = d.transaction();
+    assert!(empty.commit().await?.known.is_empty());
+    assert!(t.patches().is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn keys_are_unknown_before_connecting() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+
+    let report = d
+        .transaction()
+        .set("ext/ibank/0/ch/0/trim".parse()?, Value::Int(10))
+        .set(
+            "ext/ibank/0/ch/9/name".parse()?,
+            Value::String("Nope".into()),
+        )
+        .commit()
+        .await?;
+
+    // The cache is empty, so nothing can be vouched for even though the write went out
+    assert!(report.known.is_empty());
+    assert_eq!(report.unknown.len(), 2);
+    assert_eq!(t.patches().len(), 1);
+    assert_eq!(t.get("ext/ibank/0/ch/0/trim"), Some(json!(10)));
+
+    Ok(())
+}
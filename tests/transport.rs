@@ -0,0 +1,305 @@
+mod common;
+
+use common::{next_external, transport, wait_for_state, wait_until, WAIT};
+use motu_avb_api::transport::{HttpTransport, MemoryTransport};
+use motu_avb_api::{Backoff, ConnectionState, Device, PollingMode, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn memory_device_connects_and_builds_banks() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+
+    assert!(d.connection_state().is_connected());
+    assert_eq!(d.input_banks()?.len(), 5);
+    assert_eq!(d.output_banks()?.len(), 6);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn memory_device_patches_and_polls() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.connect().await?;
+
+    let mut updates = d.updates()?;
+    d.set_keys(&[("ext/obank/0/ch/0/trim", Value::Int(-20))])
+        .await?;
+
+    assert_eq!(t.get("ext/obank/0/ch/0/trim"), Some((-20).into()));
+    assert_eq!(t.patches().len(), 1);
+
+    // Our own patch isn't echoed back, the next external update is the front panel change
+    t.push("ext/ibank/0/ch/0/name", "Kick");
+    assert_eq!(
+        next_external(&mut updates, "ext/ibank/0/ch/0/name").await,
+        Value::String("Kick".into())
+    );
+    assert!(updates.try_recv().is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn memory_device_survives_outage() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.set_backoff(Backoff {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(50),
+        ..Default::default()
+    });
+    d.connect().await?;
+
+    t.set_offline(true);
+    wait_for_state(&d, |s| matches!(s, ConnectionState::Degraded(_))).await;
+
+    t.push("ext/obank/0/name", "Monitors");
+    t.set_offline(false);
+
+    // Coming back is a full resync which overflows the update channel, check the cache instead
+    wait_until(|| d.get_value("ext/obank/0/name") == Some(Value::String("Monitors".into()))).await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn device_can_connect_again_after_giving_up() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.set_backoff(Backoff {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(10),
+        max_retries: Some(2),
+        ..Default::default()
+    });
+    d.connect().await?;
+
+    t.set_offline(true);
+    wait_for_state(&d, |s| matches!(s, ConnectionState::Disconnected)).await;
+    assert!(d.input_banks().is_err());
+
+    t.set_offline(false);
+    d.connect().await?;
+    assert!(d.connection_state().is_connected());
+    assert_eq!(d.input_banks()?.len(), 5);
+    d.disconnect().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn failing_datastore_runs_out_of_retries() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.set_backoff(Backoff {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(10),
+        max_retries: Some(3),
+        ..Default::default()
+    });
+    d.connect().await?;
+
+    // `/apiversion` keeps answering, every reconnect looks fine until the next poll
+    t.set_datastore_failing(true);
+    wait_for_state(&d, |s| matches!(s, ConnectionState::Disconnected)).await;
+
+    t.set_datastore_failing(false);
+    d.connect().await?;
+    d.disconnect().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn bad_values_are_reported_and_skipped() -> anyhow::Result<()> {
+    let t = transport();
+    t.push("ext/ibank/0/ch/1/trim", "loud");
+    let d = t.device();
+    let mut diagnostics = d.diagnostics();
+    d.connect().await?;
+
+    // The initial pass still builds every bank around the bad key
+    let diag = timeout(WAIT, diagnostics.recv()).await??;
+    assert_eq!(diag.key, "ext/ibank/0/ch/1/trim");
+    assert_eq!(diag.raw, serde_json::json!("loud"));
+    assert_eq!(d.input_banks()?.len(), 5);
+    assert_eq!(
+        d.input_banks()?.get(&0).unwrap().channels[&0]
+            .default_name
+            .as_deref(),
+        Some("Mic 1")
+    );
+
+    // Bad updates later on don't stop the mapping task either
+    t.push_many(
+        [
+            ("ext/ibank/0/ch/0/trim".to_string(), "louder".into()),
+            ("ext/ibank/0/ch/0/name".to_string(), "Kick".into()),
+        ]
+        .into_iter()
+        .collect(),
+    );
+    let diag = timeout(WAIT, diagnostics.recv()).await??;
+    assert_eq!(diag.key, "ext/ibank/0/ch/0/trim");
+
+    wait_until(|| {
+        d.input_banks().unwrap().get(&0).unwrap().channels[&0]
+            .name
+            .as_deref()
+            == Some("Kick")
+    })
+    .await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn interval_polling_announces_changes_only() -> anyhow::Result<()> {
+    let t = transport();
+    let d = Device::builder("localhost", 0)
+        .transport(t.clone())
+        .polling(PollingMode::Interval(Duration::from_millis(20)))
+        .build()?;
+    d.connect().await?;
+
+    let mut updates = d.updates()?;
+    t.push("ext/ibank/0/ch/0/name", "Kick");
+    assert_eq!(
+        next_external(&mut updates, "ext/ibank/0/ch/0/name").await,
+        Value::String("Kick".into())
+    );
+
+    // Later polls see the same datastore and stay quiet
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(updates.try_recv().is_err());
+
+    Ok(())
+}
+
+/// Speaks just enough HTTP to connect a device, then stops answering once `stalled` is set.
+/// Long polls are never answered, like a device that is holding on to them.
+async fn stalling_server(stalled: Arc<AtomicBool>) -> anyhow::Result<std::net::SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let stalled = stalled.clone();
+            tokio::spawn(async move {
+                let mut buf = vec![0; 4096];
+                let n = socket.read(&mut buf).await.unwrap_or(0);
+                let req = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+
+                let body = if stalled.load(Ordering::SeqCst) || req.contains("if-none-match") {
+                    std::future::pending::<()>().await;
+                    unreachable!()
+                } else if req.starts_with("get /apiversion") {
+                    "0.0.0".to_string()
+                } else {
+                    serde_json::json!({ "ext/obank/0/name": "Main" }).to_string()
+                };
+
+                let res = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nETag: \"1\"\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(res.as_bytes()).await;
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+#[tokio::test]
+async fn unresponsive_device_is_degraded() -> anyhow::Result<()> {
+    let stalled = Arc::new(AtomicBool::new(false));
+    let addr = stalling_server(stalled.clone()).await?;
+
+    let t = HttpTransport::new(&addr.ip().to_string(), addr.port())
+        .request_timeout(Duration::from_millis(200))
+        .long_poll_timeout(Duration::from_millis(200));
+    let d = Device::builder("localhost", 0).transport(t).build()?;
+    d.set_backoff(Backoff {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(50),
+        ..Default::default()
+    });
+    d.connect().await?;
+
+    // Long polls that run out while the device still answers are not errors
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(d.connection_state().is_connected());
+
+    // The device stops answering altogether, which must not hang the polling loop
+    stalled.store(true, Ordering::SeqCst);
+    wait_for_state(&d, |s| matches!(s, ConnectionState::Degraded(_))).await;
+
+    Ok(())
+}
+
+/// Renames every channel of every bank at once, far more keys than the update channel holds
+fn rename_all(t: &MemoryTransport, prefix: &str) {
+    let names = t
+        .values()
+        .into_keys()
+        .filter(|k| k.starts_with("ext/") && k.contains("/ch/") && k.ends_with("/name"))
+        .map(|k| {
+            let v = format!("{} {}", prefix, k);
+            (k, v.into())
+        })
+        .collect::<std::collections::HashMap<_, _>>();
+    assert!(names.len() > 64);
+    t.push_many(names);
+}
+
+fn banks_renamed(d: &Device, prefix: &str) -> bool {
+    let banks = [
+        ("ibank", d.input_banks().unwrap()),
+        ("obank", d.output_banks().unwrap()),
+    ];
+    banks.iter().all(|(t, banks)| {
+        banks.iter().all(|b| {
+            b.channels.iter().all(|(i, c)| {
+                let expected = format!("{} ext/{}/{}/ch/{}/name", prefix, t, b.index, i);
+                c.name.as_deref() == Some(expected.as_str())
+            })
+        })
+    })
+}
+
+#[tokio::test]
+async fn banks_converge_after_lagging() -> anyhow::Result<()> {
+    let t = transport();
+    let d = t.device();
+    d.set_backoff(Backoff {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(50),
+        ..Default::default()
+    });
+    d.connect().await?;
+
+    rename_all(&t, "first");
+    wait_until(|| banks_renamed(&d, "first")).await;
+
+    // Same again while the device is away, the resync after reconnecting has to land
+    // even though the mapping task lagged before
+    t.set_offline(true);
+    wait_for_state(&d, |s| matches!(s, ConnectionState::Degraded(_))).await;
+
+    rename_all(&t, "second");
+    t.set_offline(false);
+    wait_until(|| banks_renamed(&d, "second")).await;
+
+    Ok(())
+}
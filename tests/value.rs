@@ -0,0 +1,180 @@
+use motu_avb_api::{MEnum, RawValue, Value};
+use proptest::prelude::*;
+use serde_json::{json, Value as SerdeValue};
+
+fn decode(key: &str, raw: SerdeValue) -> Value {
+    Value::try_from(raw).and_then(|v| v.decode(key)).unwrap()
+}
+
+fn roundtrip(key: &str, raw: &SerdeValue) -> Result<(), TestCaseError> {
+    let v = Value::try_from(raw.clone())
+        .and_then(|v| v.decode(key))
+        .map_err(|e| TestCaseError::fail(e.to_string()))?;
+
+    prop_assert_eq!(&v.encode(), raw);
+    prop_assert_eq!(&serde_json::to_value(&v).unwrap(), raw);
+    Ok(())
+}
+
+fn keys() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just("ext/obank/0/ch/0/trimRange".to_string()),
+        Just("ext/ibank/0/ch/0/name".to_string()),
+        Just("ext/obank/0/ch/1/src".to_string()),
+        Just("mix/chan/0/matrix/mute".to_string()),
+        "[a-z0-9/]{1,32}",
+    ]
+}
+
+fn enum_strings() -> impl Strategy<Value = String> {
+    (
+        any::<i64>(),
+        prop::collection::vec((any::<i64>(), "[^:]*"), 0..4),
+    )
+        .prop_map(|(v, defs)| {
+            let mut s = format!("enum:{}", v);
+            for (k, name) in defs {
+                s.push_str(&format!(":{}={}", k, name));
+            }
+            s
+        })
+}
+
+fn raw_values() -> impl Strategy<Value = SerdeValue> {
+    prop_oneof![
+        any::<i64>().prop_map(SerdeValue::from),
+        any::<u32>().prop_map(SerdeValue::from),
+        any::<f64>()
+            .prop_filter("json has no nan or infinity", |v| v.is_finite())
+            .prop_map(SerdeValue::from),
+        any::<bool>().prop_map(SerdeValue::from),
+        any::<String>().prop_map(SerdeValue::from),
+        "[-0-9a-z:=]{0,24}".prop_map(SerdeValue::from),
+        enum_strings().prop_map(SerdeValue::from),
+        (any::<i32>(), any::<i32>()).prop_map(|(a, b)| SerdeValue::from(format!("{}:{}", a, b))),
+        any::<u64>().prop_map(SerdeValue::from),
+        Just(SerdeValue::Null),
+        prop::collection::vec(any::<i64>(), 0..4).prop_map(SerdeValue::from),
+        prop::collection::hash_map("[a-z]{1,8}", any::<String>(), 0..4)
+            .prop_map(|m| SerdeValue::Object(m.into_iter().map(|(k, v)| (k, v.into())).collect())),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn decode_encode_roundtrip(key in keys(), raw in raw_values()) {
+        roundtrip(&key, &raw)?;
+    }
+
+    #[test]
+    fn well_formed_enums_decode(s in enum_strings()) {
+        prop_assert!(matches!(decode("ext/clockSource", s.into()), Value::Enum(_)));
+    }
+
+    #[test]
+    fn names_keep_their_colons(name in ".*") {
+        prop_assert_eq!(
+            decode("ext/ibank/0/ch/0/name", name.clone().into()),
+            Value::String(name)
+        );
+    }
+}
+
+#[test]
+fn decodes_motu_encodings() {
+    assert_eq!(decode("ext/obank/0/ch/0/trim", json!(-30)), Value::Int(-30));
+    assert_eq!(
+        decode("mix/chan/1/matrix/fader", json!(0.5)),
+        Value::Float(0.5)
+    );
+    assert_eq!(
+        decode("ext/obank/0/ch/0/trimRange", json!("-127:0")),
+        Value::Pair(vec!["-127".into(), "0".into()])
+    );
+    assert_eq!(
+        decode("ext/ibank/0/ch/0/name", json!("Kick: In")),
+        Value::String("Kick: In".into())
+    );
+    assert_eq!(
+        decode("ext/ibank/0/smux", json!("adat")),
+        Value::String("adat".into())
+    );
+    assert_eq!(
+        decode("ext/clockSource", json!("enum:1:0=Internal:1=Word Clock")),
+        Value::Enum(MEnum {
+            value: 1,
+            definitions: vec![(0, "Internal".into()), (1, "Word Clock".into())],
+        })
+    );
+}
+
+#[test]
+fn names_in_the_schema_stay_strings() {
+    for key in [
+        "avb/0001f2fffe00624a/vendor_name",
+        "avb/0001f2fffe00624a/cfg/0/input_streams/0/name",
+        "avb/0001f2fffe00624a/cfg/0/clock_sources/1/object_name",
+    ] {
+        assert_eq!(
+            decode(key, json!("MOTU: 624")),
+            Value::String("MOTU: 624".into()),
+            "{}",
+            key
+        );
+    }
+
+    // Like any other unknown key, a name the schema doesn't cover is kept raw
+    let v = decode("avb/0001f2fffe00624a/cfg/0/someName", json!("MOTU: 624"));
+    assert!(matches!(v, Value::Raw(_)));
+    assert_eq!(v.encode(), json!("MOTU: 624"));
+}
+
+#[test]
+fn unknown_encodings_are_kept_raw() {
+    let pair = Value::Pair(vec!["enum".into(), "01".into(), "0=Internal".into()]);
+    assert_eq!(
+        decode("ext/clockSource", json!("enum:01:0=Internal")),
+        Value::Raw(RawValue {
+            raw: json!("enum:01:0=Internal"),
+            guess: Some(Box::new(pair)),
+        })
+    );
+
+    let v = decode("avb/0001f2fffe000000/entity_model_id", json!("ab:cd"));
+    assert!(matches!(v, Value::Raw(_)));
+    assert_eq!(v.to_string(), "ab:cd");
+
+    for raw in [json!([1, 2]), json!({"a": 1}), json!(null)] {
+        let v = decode("ext/unknown", raw.clone());
+        assert_eq!(v, Value::Raw(RawValue { raw, guess: None }));
+    }
+
+    // Bigger than an i64, the guess is only approximate but the raw value is exact
+    let v = decode("ext/big", json!(u64::MAX));
+    assert_eq!(v.encode(), json!(u64::MAX));
+    assert!(u32::try_from(&v).is_err());
+}
+
+#[test]
+fn raw_values_cast_through_their_guess() {
+    let v = decode("ext/obank/0/ch/0/weird", json!("-1:x"));
+    assert!(matches!(v, Value::Raw(_)));
+    assert!(<(i32, i32)>::try_from(&v).is_err());
+
+    let v = decode("ext/obank/0/ch/0/trimRange", json!("-127:0"));
+    assert_eq!(<(i32, i32)>::try_from(&v).unwrap(), (-127, 0));
+}
+
+#[test]
+fn malformed_values_return_errors() {
+    let v = decode("ext/obank/0/ch/0/trimRange", json!("-127:0:5"));
+    assert!(<(i32, i32)>::try_from(&v).is_err());
+    assert!(<(i32, i32)>::try_from(&Value::Pair(vec!["1".into()])).is_err());
+    assert!(u32::try_from(&Value::Int(-1)).is_err());
+    assert!(i32::try_from(&Value::Int(i64::MAX)).is_err());
+
+    for s in ["enum:", "enum:1:", "enum:1:=", "enum:x:1=a", ":", "::"] {
+        let v = decode("ext/clockSource", json!(s));
+        assert_eq!(v.encode(), json!(s));
+    }
+}
@@ -0,0 +1,80 @@
+use motu_avb_api::{Pattern, PatternError};
+
+fn captures(pattern: &str, key: &str) -> Option<Vec<String>> {
+    let p: Pattern = pattern.parse().unwrap();
+    p.matches(key)
+        .map(|c| c.iter().map(|v| v.to_string()).collect())
+}
+
+#[test]
+fn literals_match_exactly() {
+    assert_eq!(captures("ext/clockSource", "ext/clockSource"), Some(vec![]));
+    assert_eq!(captures("ext/clockSource", "ext/clockLocked"), None);
+    assert_eq!(captures("ext/clockSource", "ext"), None);
+    assert_eq!(captures("ext", "ext/clockSource"), None);
+}
+
+#[test]
+fn star_matches_one_segment() -> anyhow::Result<()> {
+    let p: Pattern = "ext/obank/*/ch/*/trim".parse()?;
+
+    let c = p.matches("ext/obank/2/ch/11/trim").unwrap();
+    assert_eq!(c.len(), 2);
+    assert_eq!(c.get(0), Some("2"));
+    assert_eq!(c.index(1), Some(11));
+    assert_eq!(c.get(2), None);
+
+    assert!(p.matches("ext/obank/2/ch/trim").is_none());
+    assert!(p.matches("ext/obank/2/ch/11/trim/x").is_none());
+    assert!(p.matches("ext/ibank/2/ch/11/trim").is_none());
+
+    // Captures don't have to be numbers
+    let c = p.matches("ext/obank/x/ch/11/trim").unwrap();
+    assert_eq!(c.get(0), Some("x"));
+    assert_eq!(c.index(0), None);
+
+    Ok(())
+}
+
+#[test]
+fn double_star_captures_the_rest() {
+    assert_eq!(
+        captures("mix/chan/*/**", "mix/chan/3/matrix/fader"),
+        Some(vec!["3".to_string(), "matrix/fader".to_string()])
+    );
+    assert_eq!(
+        captures("mix/chan/3/**", "mix/chan/3/mute"),
+        Some(vec!["mute".to_string()])
+    );
+    assert_eq!(captures("**", "uid"), Some(vec!["uid".to_string()]));
+    assert_eq!(captures("mix/chan/3/**", "mix/chan/4/mute"), None);
+}
+
+#[test]
+fn double_star_needs_at_least_one_segment() {
+    assert_eq!(captures("mix/chan/3/**", "mix/chan/3"), None);
+}
+
+#[test]
+fn malformed_patterns_are_rejected() {
+    assert!(matches!("".parse::<Pattern>(), Err(PatternError::Empty)));
+    assert!(matches!("/".parse::<Pattern>(), Err(PatternError::Empty)));
+    assert!(matches!(
+        "ext//trim".parse::<Pattern>(),
+        Err(PatternError::EmptySegment(_))
+    ));
+    assert!(matches!(
+        "mix/**/fader".parse::<Pattern>(),
+        Err(PatternError::RestNotLast(_))
+    ));
+}
+
+#[test]
+fn patterns_display_as_parsed() -> anyhow::Result<()> {
+    for s in ["ext/obank/*/ch/*/trim", "mix/chan/3/**", "uid"] {
+        assert_eq!(s.parse::<Pattern>()?.to_string(), s);
+    }
+    assert_eq!("/ext/*/".parse::<Pattern>()?.to_string(), "ext/*");
+
+    Ok(())
+}